@@ -0,0 +1,393 @@
+use crate::*;
+
+/// a token approved for a bundle
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleItem {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub approval_id: U64,
+    pub token_type: TokenType,
+}
+
+/// tokens sold together at one price, possibly from different NFT contracts. Every approval
+/// naming bundle_id adds an item and the bundle is for sale once it has size items
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Bundle {
+    pub owner_id: AccountId,
+    pub bundle_id: String,
+    pub size: u8,
+    pub items: Vec<BundleItem>,
+    pub sale_conditions: SaleConditions,
+    pub created_at: U64,
+}
+
+impl Bundle {
+    pub fn is_complete(&self) -> bool {
+        self.items.len() == self.size as usize
+    }
+
+    /// price split evenly across the items, the first item takes the remainder
+    pub fn item_prices(&self, price: Balance) -> Vec<Balance> {
+        let count = self.items.len() as u128;
+        let mut prices = vec![price / count; self.items.len()];
+        prices[0] += price % count;
+        prices
+    }
+
+    /// held from the owner's storage deposit while the bundle is bought, see trade_storage_max
+    pub fn trade_storage_max(&self) -> StorageUsage {
+        self.items
+            .iter()
+            .map(|item| {
+                trade_storage_max(
+                    &item.nft_contract_id,
+                    &item.token_id,
+                    &self.owner_id,
+                    item.token_type.as_ref(),
+                )
+            })
+            .sum()
+    }
+}
+
+/// approve with {"bundle_id": "...", "size": 3, "sale_conditions": {...}} to add the token to
+/// one of the owner's bundles, the latest approval's sale_conditions are the bundle's price
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleArgs {
+    pub bundle_id: String,
+    pub size: u8,
+    pub sale_conditions: SaleConditions,
+    /// added by the NFT contract
+    pub token_type: TokenType,
+}
+
+/// ft_on_transfer msg to buy a bundle in FTs: {"bundle": "owner.near||bundle_id"}
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BuyBundleArgs {
+    pub bundle: OwnerAndBundleId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer_id: Option<ValidAccountId>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// for adding items see: nft_callbacks.rs
+
+    /// buy a complete bundle with the attached deposit, needs gas to move every item twice
+    #[payable]
+    pub fn buy_bundle(
+        &mut self,
+        bundle: OwnerAndBundleId,
+        referrer_id: Option<ValidAccountId>,
+    ) -> Promise {
+        self.internal_buy_bundle(
+            bundle,
+            "near".to_string(),
+            env::attached_deposit(),
+            env::predecessor_account_id(),
+            referrer_id.map(|referrer_id| referrer_id.into()),
+        )
+    }
+
+    #[payable]
+    pub fn remove_bundle(&mut self, bundle_id: String) {
+        assert_one_yocto();
+        let owner_and_bundle_id = format!(
+            "{}{}{}",
+            env::predecessor_account_id(),
+            DELIMETER,
+            bundle_id
+        );
+        self.internal_remove_bundle(&owner_and_bundle_id);
+    }
+
+    /// self callback
+
+    /// the market holds every item that transferred, it sends them on to the buyer if all
+    /// of them did and their payouts are valid, otherwise back to the seller
+    #[private]
+    pub fn resolve_bundle_purchase(
+        &mut self,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        bundle: Bundle,
+        price: U128,
+        referrer_id: Option<AccountId>,
+    ) -> U128 {
+        let results: Vec<Option<Vec<u8>>> = (0..bundle.items.len())
+            .map(|index| match env::promise_result(index as u64) {
+                PromiseResult::Successful(value) => Some(value),
+                _ => None,
+            })
+            .collect();
+
+        let item_payouts: Vec<Option<Payout>> = results
+            .iter()
+            .zip(bundle.item_prices(price.0))
+            .map(|(result, item_price)| {
+                result
+                    .as_ref()
+                    .and_then(|value| parse_payout(value, item_price))
+            })
+            .collect();
+        let mut is_valid = item_payouts.iter().all(Option::is_some);
+        let receivers: HashSet<&AccountId> = item_payouts
+            .iter()
+            .flatten()
+            .flat_map(|item_payout| item_payout.keys())
+            .collect();
+        let referral_receivers = usize::from(self.referral_fee_bps(referrer_id.as_ref()) > 0);
+        if is_valid && receivers.len() + referral_receivers > 10 {
+            env::log("Cannot have more than 10 royalties and referrer".as_bytes());
+            is_valid = false;
+        }
+
+        let receiver_id = if is_valid {
+            &buyer_id
+        } else {
+            &bundle.owner_id
+        };
+        for (item, result) in bundle.items.iter().zip(&results) {
+            if result.is_some() {
+                ext_contract::nft_transfer(
+                    receiver_id.clone(),
+                    item.token_id.clone(),
+                    None,
+                    None,
+                    &item.nft_contract_id,
+                    1,
+                    GAS_FOR_NFT_TRANSFER,
+                );
+            }
+        }
+
+        let mut purchases: Vec<PurchaseData> = bundle
+            .items
+            .iter()
+            .zip(bundle.item_prices(price.0))
+            .map(|(item, item_price)| PurchaseData {
+                owner_id: &bundle.owner_id,
+                buyer_id: &buyer_id,
+                nft_contract_id: &item.nft_contract_id,
+                token_id: &item.token_id,
+                bundle_id: Some(&bundle.bundle_id),
+                ft_token_id: &ft_token_id,
+                price: U128(item_price),
+                paid_out: None,
+            })
+            .collect();
+        let initial_storage_usage = env::storage_usage();
+        if !is_valid {
+            transfer_to(&ft_token_id, &buyer_id, price);
+            MarketEvent::PurchaseFailed(purchases).emit();
+            self.internal_settle_held_storage(
+                &bundle.owner_id,
+                bundle.trade_storage_max(),
+                initial_storage_usage,
+            );
+            return price;
+        }
+        // every item pays its own royalties and fees, the transfers are made once per receiver
+        let mut paid_out = PaidOut {
+            payout: Payout::new(),
+            protocol_fee: U128(0),
+            referrer_id: referrer_id.clone(),
+            referral_fee: U128(0),
+        };
+        for ((purchase, item_payout), item_price) in purchases
+            .iter_mut()
+            .zip(item_payouts)
+            .zip(bundle.item_prices(price.0))
+        {
+            let item_paid_out =
+                self.internal_paid_out(item_payout.unwrap(), item_price, referrer_id.clone());
+            for (receiver_id, amount) in &item_paid_out.payout {
+                paid_out
+                    .payout
+                    .entry(receiver_id.clone())
+                    .or_insert(U128(0))
+                    .0 += amount.0;
+            }
+            paid_out.protocol_fee.0 += item_paid_out.protocol_fee.0;
+            paid_out.referral_fee.0 += item_paid_out.referral_fee.0;
+            purchase.paid_out = Some(item_paid_out);
+        }
+        self.internal_transfer_paid_out(&ft_token_id, &paid_out, price.0);
+        MarketEvent::PurchaseSucceeded(purchases).emit();
+        let traded_at = U64(current_time_ms());
+        for (item, item_price) in bundle.items.iter().zip(bundle.item_prices(price.0)) {
+            let trade = Trade {
+                nft_contract_id: item.nft_contract_id.clone(),
+                token_id: item.token_id.clone(),
+                seller_id: bundle.owner_id.clone(),
+                buyer_id: buyer_id.clone(),
+                ft_token_id: ft_token_id.clone(),
+                price: U128(item_price),
+                traded_at,
+            };
+            self.internal_record_stats(&trade, item.token_type.as_ref());
+            self.internal_record_trade(trade);
+        }
+        self.internal_settle_held_storage(
+            &bundle.owner_id,
+            bundle.trade_storage_max(),
+            initial_storage_usage,
+        );
+        U128(0)
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_add_bundle_item(
+        &mut self,
+        owner_id: AccountId,
+        item: BundleItem,
+        bundle_args: BundleArgs,
+    ) {
+        let BundleArgs {
+            bundle_id,
+            size,
+            sale_conditions,
+            ..
+        } = bundle_args;
+        if let Some(token_type) = &item.token_type {
+            assert!(
+                item.token_id.contains(token_type),
+                "TokenType should be substr of TokenId"
+            );
+        }
+        assert!(
+            (2..=BUNDLE_SIZE_MAX).contains(&size),
+            "Bundles have 2 to {} items",
+            BUNDLE_SIZE_MAX
+        );
+        for ft_token_id in sale_conditions.keys() {
+            if !self.ft_token_ids.contains(ft_token_id) {
+                env::panic(
+                    format!("Token {} not supported by this market", ft_token_id).as_bytes(),
+                );
+            }
+        }
+        self.internal_remove_stale_sale(item.nft_contract_id.clone(), item.token_id.clone());
+
+        let owner_and_bundle_id = format!("{}{}{}", owner_id, DELIMETER, bundle_id);
+        let mut bundle = self
+            .bundles
+            .get(&owner_and_bundle_id)
+            .unwrap_or_else(|| Bundle {
+                owner_id: owner_id.clone(),
+                bundle_id,
+                size,
+                items: vec![],
+                sale_conditions: HashMap::new(),
+                created_at: U64(current_time_ms()),
+            });
+        assert_eq!(bundle.size, size, "Bundle has {} items", bundle.size);
+        bundle.sale_conditions = sale_conditions;
+
+        let existing_item = bundle.items.iter_mut().find(|existing_item| {
+            existing_item.nft_contract_id == item.nft_contract_id
+                && existing_item.token_id == item.token_id
+        });
+        if let Some(existing_item) = existing_item {
+            existing_item.approval_id = item.approval_id;
+            existing_item.token_type = item.token_type;
+        } else {
+            assert!(!bundle.is_complete(), "Bundle is complete");
+            self.assert_storage_for_one_more(&owner_id);
+            bundle.items.push(item);
+        }
+        self.bundles.insert(&owner_and_bundle_id, &bundle);
+
+        let mut bundles_by_owner_id =
+            self.bundles_by_owner_id.get(&owner_id).unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::BundlesByOwnerIdInner {
+                        account_id_hash: hash_account_id(&owner_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        bundles_by_owner_id.insert(&owner_and_bundle_id);
+        self.bundles_by_owner_id
+            .insert(&owner_id, &bundles_by_owner_id);
+    }
+
+    pub(crate) fn internal_remove_bundle(&mut self, owner_and_bundle_id: &str) -> Bundle {
+        let bundle = self
+            .bundles
+            .remove(&owner_and_bundle_id.to_string())
+            .expect("No bundle");
+        let mut bundles_by_owner_id = self
+            .bundles_by_owner_id
+            .get(&bundle.owner_id)
+            .expect("No bundle by owner_id");
+        bundles_by_owner_id.remove(&owner_and_bundle_id.to_string());
+        if bundles_by_owner_id.is_empty() {
+            self.bundles_by_owner_id.remove(&bundle.owner_id);
+        } else {
+            self.bundles_by_owner_id
+                .insert(&bundle.owner_id, &bundles_by_owner_id);
+        }
+        bundle
+    }
+
+    /// the market takes every item first, so that a failed transfer can be rolled back
+    pub(crate) fn internal_buy_bundle(
+        &mut self,
+        owner_and_bundle_id: OwnerAndBundleId,
+        ft_token_id: AccountId,
+        amount: Balance,
+        buyer_id: AccountId,
+        referrer_id: Option<AccountId>,
+    ) -> Promise {
+        let bundle = self.bundles.get(&owner_and_bundle_id).expect("No bundle");
+        assert!(bundle.is_complete(), "Bundle is not complete");
+        assert_ne!(bundle.owner_id, buyer_id, "Cannot buy your own bundle");
+        let price = bundle
+            .sale_conditions
+            .get(&ft_token_id)
+            .unwrap_or_else(|| env::panic(format!("Not for sale in {}", ft_token_id).as_bytes()))
+            .0;
+        assert_eq!(amount, price, "Must pay the bundle price {}", price);
+        let referrer_id = referrer_id.filter(|referrer_id| referrer_id != &buyer_id);
+        let bundle = self.internal_remove_bundle(&owner_and_bundle_id);
+        self.internal_hold_storage(&bundle.owner_id, bundle.trade_storage_max());
+
+        let market_id = env::current_account_id();
+        let transfers = bundle
+            .items
+            .iter()
+            .zip(bundle.item_prices(price))
+            .map(|(item, item_price)| {
+                ext_contract::nft_transfer_payout(
+                    market_id.clone(),
+                    item.token_id.clone(),
+                    item.approval_id,
+                    None,
+                    U128(item_price),
+                    &item.nft_contract_id,
+                    1,
+                    GAS_FOR_NFT_TRANSFER,
+                )
+            })
+            .reduce(|transfers, transfer| transfers.and(transfer))
+            .unwrap();
+        let gas_for_resolve = GAS_FOR_ROYALTIES + GAS_FOR_NFT_TRANSFER * bundle.items.len() as u64;
+        transfers.then(ext_self::resolve_bundle_purchase(
+            ft_token_id,
+            buyer_id,
+            bundle,
+            U128(price),
+            referrer_id,
+            &market_id,
+            NO_DEPOSIT,
+            gas_for_resolve,
+        ))
+    }
+}
@@ -0,0 +1,55 @@
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// views
+    pub fn get_supply_bundles(&self) -> U64 {
+        U64(self.bundles.len())
+    }
+
+    pub fn get_bundle(&self, owner_and_bundle_id: OwnerAndBundleId) -> Option<Bundle> {
+        self.bundles.get(&owner_and_bundle_id)
+    }
+
+    pub fn get_bundles(&self, from_index: U64, limit: u64) -> Vec<Bundle> {
+        let mut tmp = vec![];
+        let values = self.bundles.values_as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + limit, values.len());
+        for i in start..end {
+            tmp.push(values.get(i).unwrap());
+        }
+        tmp
+    }
+
+    pub fn get_supply_bundles_by_owner_id(&self, account_id: AccountId) -> U64 {
+        let bundles_by_owner_id = self.bundles_by_owner_id.get(&account_id);
+        if let Some(bundles_by_owner_id) = bundles_by_owner_id {
+            U64(bundles_by_owner_id.len())
+        } else {
+            U64(0)
+        }
+    }
+
+    pub fn get_bundles_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Bundle> {
+        let mut tmp = vec![];
+        let bundles_by_owner_id = self.bundles_by_owner_id.get(&account_id);
+        let bundles = if let Some(bundles_by_owner_id) = bundles_by_owner_id {
+            bundles_by_owner_id
+        } else {
+            return vec![];
+        };
+        let keys = bundles.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + limit, bundles.len());
+        for i in start..end {
+            tmp.push(self.bundles.get(&keys.get(i).unwrap()).unwrap());
+        }
+        tmp
+    }
+}
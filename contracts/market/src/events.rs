@@ -0,0 +1,187 @@
+use crate::*;
+
+/// NEP-297 events, logged as
+/// EVENT_JSON:{"standard":"nft_market","version":"1.0.0","event":"bid_placed","data":[...]}
+const EVENT_STANDARD: &str = "nft_market";
+const EVENT_VERSION: &str = "1.0.0";
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum MarketEvent<'a> {
+    SaleListed(Vec<SaleListedData<'a>>),
+    SaleUpdated(Vec<SaleUpdatedData<'a>>),
+    SaleRemoved(Vec<SaleRemovedData<'a>>),
+    /// price is the deposit for sealed bids
+    BidPlaced(Vec<BidData<'a>>),
+    BidRefunded(Vec<BidData<'a>>),
+    PurchaseSucceeded(Vec<PurchaseData<'a>>),
+    PurchaseFailed(Vec<PurchaseData<'a>>),
+    /// a late bid pushed back auction_end_at
+    AuctionExtended(Vec<AuctionExtendedData<'a>>),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a MarketEvent<'a>,
+}
+
+impl MarketEvent<'_> {
+    pub fn emit(&self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_VERSION,
+            event: self,
+        };
+        env::log(
+            format!(
+                "EVENT_JSON:{}",
+                near_sdk::serde_json::to_string(&log).unwrap()
+            )
+            .as_bytes(),
+        );
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleListedData<'a> {
+    pub owner_id: &'a str,
+    pub nft_contract_id: &'a str,
+    pub token_id: &'a str,
+    pub approval_id: U64,
+    pub sale_conditions: &'a SaleConditions,
+    pub is_auction: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starts_at: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction_end_at: Option<U64>,
+    /// left out while the reserve is hidden
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserve_price: Option<&'a SaleConditions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buy_now_price: Option<&'a SaleConditions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dutch_auction: Option<&'a DutchAuction>,
+    pub is_sealed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_ids: Option<&'a Vec<AccountId>>,
+}
+
+impl<'a> SaleListedData<'a> {
+    pub fn new(sale: &'a Sale) -> Self {
+        Self {
+            owner_id: &sale.owner_id,
+            nft_contract_id: &sale.nft_contract_id,
+            token_id: &sale.token_id,
+            approval_id: sale.approval_id,
+            sale_conditions: &sale.sale_conditions,
+            is_auction: sale.is_auction,
+            token_type: sale.token_type.as_deref(),
+            starts_at: sale.starts_at,
+            expires_at: sale.expires_at,
+            auction_end_at: sale.auction_end_at,
+            reserve_price: sale
+                .reserve_price
+                .as_ref()
+                .filter(|_| !sale.is_reserve_hidden),
+            buy_now_price: sale.buy_now_price.as_ref(),
+            dutch_auction: sale.dutch_auction.as_ref(),
+            is_sealed: sale.sealed_bid.is_some(),
+            buyer_ids: sale.buyer_ids.as_ref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionExtendedData<'a> {
+    pub nft_contract_id: &'a str,
+    pub token_id: &'a str,
+    pub auction_end_at: U64,
+}
+
+/// the new price of a sale in ft_token_id
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleUpdatedData<'a> {
+    pub nft_contract_id: &'a str,
+    pub token_id: &'a str,
+    pub ft_token_id: &'a str,
+    pub price: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleRemovedData<'a> {
+    pub owner_id: &'a str,
+    pub nft_contract_id: &'a str,
+    pub token_id: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BidData<'a> {
+    pub bidder_id: &'a str,
+    pub nft_contract_id: &'a str,
+    pub token_id: &'a str,
+    pub ft_token_id: &'a str,
+    pub price: U128,
+}
+
+impl<'a> BidData<'a> {
+    /// bid on the sale at contract_and_token_id
+    pub fn new(
+        contract_and_token_id: &'a str,
+        bidder_id: &'a str,
+        ft_token_id: &'a str,
+        price: U128,
+    ) -> Self {
+        let (nft_contract_id, token_id) = contract_and_token_id
+            .split_once(DELIMETER)
+            .expect("Not a contract_and_token_id");
+        Self {
+            bidder_id,
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            price,
+        }
+    }
+}
+
+/// where the price of a purchase went, after the protocol and referral fees
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PaidOut {
+    pub payout: Payout,
+    pub protocol_fee: U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer_id: Option<AccountId>,
+    pub referral_fee: U128,
+}
+
+/// paid_out is None for a failed purchase, the price went back to the buyer. A bundle
+/// purchase has one entry per item with its share of the price and its own paid_out
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurchaseData<'a> {
+    pub owner_id: &'a str,
+    pub buyer_id: &'a str,
+    pub nft_contract_id: &'a str,
+    pub token_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_id: Option<&'a str>,
+    pub ft_token_id: &'a str,
+    pub price: U128,
+    #[serde(flatten)]
+    pub paid_out: Option<PaidOut>,
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::too_many_arguments)]
+
 use crate::*;
 
 /// external contract calls
@@ -12,5 +14,50 @@ trait ExtContract {
         memo: Option<String>,
         balance: U128,
     );
+    fn nft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approval_id: Option<U64>,
+        memo: Option<String>,
+    );
+    fn nft_token(&self, token_id: TokenId);
+    fn nft_is_approved(
+        &self,
+        token_id: TokenId,
+        approved_account_id: AccountId,
+        approval_id: Option<U64>,
+    );
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
 }
+
+/// self call
+
+#[ext_contract(ext_self)]
+trait ExtSelf {
+    fn resolve_purchase(
+        &mut self,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        sale: Sale,
+        price: U128,
+        referrer_id: Option<AccountId>,
+    ) -> Promise;
+    fn resolve_bundle_purchase(
+        &mut self,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        bundle: Bundle,
+        price: U128,
+        referrer_id: Option<AccountId>,
+    ) -> Promise;
+    fn resolve_swap(&mut self, swap: Swap) -> Promise;
+    fn resolve_withdraw_protocol_fees(&mut self, ft_token_id: AccountId, amount: U128) -> bool;
+    fn resolve_verify_sale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+    ) -> bool;
+}
@@ -0,0 +1,172 @@
+use crate::*;
+
+/// treasury ledger of protocol fees in one token, the balance held is accrued - withdrawn
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ProtocolFees {
+    pub accrued: U128,
+    pub withdrawn: U128,
+}
+
+impl Default for ProtocolFees {
+    fn default() -> Self {
+        Self {
+            accrued: U128(0),
+            withdrawn: U128(0),
+        }
+    }
+}
+
+/// purchases a referrer brought in, amounts by token
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralStats {
+    pub purchases: U64,
+    pub volume: SaleConditions,
+    pub earned: SaleConditions,
+}
+
+impl Default for ReferralStats {
+    fn default() -> Self {
+        Self {
+            purchases: U64(0),
+            volume: HashMap::new(),
+            earned: HashMap::new(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// fee on every purchase price, in basis points
+    pub fn set_protocol_fee_bps(&mut self, protocol_fee_bps: u16) {
+        self.assert_owner();
+        assert!(
+            protocol_fee_bps as u32 + self.referral_fee_bps as u32 <= 10_000,
+            "Protocol and referral fees can't be more than 10000 basis points"
+        );
+        self.protocol_fee_bps = protocol_fee_bps;
+    }
+
+    /// referrer's share of every purchase price they bring in, in basis points
+    pub fn set_referral_fee_bps(&mut self, referral_fee_bps: u16) {
+        self.assert_owner();
+        assert!(
+            self.protocol_fee_bps as u32 + referral_fee_bps as u32 <= 10_000,
+            "Protocol and referral fees can't be more than 10000 basis points"
+        );
+        self.referral_fee_bps = referral_fee_bps;
+    }
+
+    /// send unwithdrawn fees in ft_token_id to the owner, all of them if amount is None.
+    /// resolve_withdraw_protocol_fees puts them back if the transfer fails
+    #[payable]
+    pub fn withdraw_protocol_fees(
+        &mut self,
+        ft_token_id: ValidAccountId,
+        amount: Option<U128>,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let ft_token_id: AccountId = ft_token_id.into();
+        let mut fees = self.protocol_fees.get(&ft_token_id).unwrap_or_default();
+        let available = fees.accrued.0 - fees.withdrawn.0;
+        let amount = amount.map_or(available, |amount| amount.0);
+        assert!(amount > 0, "Nothing to withdraw");
+        assert!(
+            amount <= available,
+            "Only {} {} can be withdrawn",
+            available,
+            ft_token_id
+        );
+        fees.withdrawn = U128(fees.withdrawn.0 + amount);
+        self.protocol_fees.insert(&ft_token_id, &fees);
+        let transfer = if ft_token_id == "near" {
+            Promise::new(self.owner_id.clone()).transfer(amount)
+        } else {
+            ext_contract::ft_transfer(
+                self.owner_id.clone(),
+                U128(amount),
+                None,
+                &ft_token_id,
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+        };
+        transfer.then(ext_self::resolve_withdraw_protocol_fees(
+            ft_token_id,
+            U128(amount),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_WITHDRAW,
+        ))
+    }
+
+    /// self callback, a failed transfer leaves the fees in the treasury
+    #[private]
+    pub fn resolve_withdraw_protocol_fees(&mut self, ft_token_id: AccountId, amount: U128) -> bool {
+        if matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            return true;
+        }
+        let mut fees = self.protocol_fees.get(&ft_token_id).unwrap_or_default();
+        fees.withdrawn = U128(fees.withdrawn.0 - amount.0);
+        self.protocol_fees.insert(&ft_token_id, &fees);
+        false
+    }
+
+    /// views
+
+    pub fn get_protocol_fee_bps(&self) -> u16 {
+        self.protocol_fee_bps
+    }
+
+    pub fn get_referral_fee_bps(&self) -> u16 {
+        self.referral_fee_bps
+    }
+
+    pub fn get_referral_stats(&self, account_id: AccountId) -> ReferralStats {
+        self.referral_stats.get(&account_id).unwrap_or_default()
+    }
+
+    pub fn get_protocol_fees(&self, ft_token_id: AccountId) -> ProtocolFees {
+        self.protocol_fees.get(&ft_token_id).unwrap_or_default()
+    }
+
+    /// fees of every supported token
+    pub fn get_all_protocol_fees(&self) -> HashMap<FungibleTokenId, ProtocolFees> {
+        self.ft_token_ids
+            .iter()
+            .map(|ft_token_id| {
+                let fees = self.protocol_fees.get(&ft_token_id).unwrap_or_default();
+                (ft_token_id, fees)
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_accrue_protocol_fee(&mut self, ft_token_id: &AccountId, fee: Balance) {
+        if fee == 0 {
+            return;
+        }
+        let mut fees = self.protocol_fees.get(ft_token_id).unwrap_or_default();
+        fees.accrued = U128(fees.accrued.0 + fee);
+        self.protocol_fees.insert(ft_token_id, &fees);
+    }
+
+    pub(crate) fn internal_record_referral(
+        &mut self,
+        referrer_id: &AccountId,
+        ft_token_id: &AccountId,
+        price: Balance,
+        referral_fee: Balance,
+    ) {
+        let mut stats = self.referral_stats.get(referrer_id).unwrap_or_default();
+        stats.purchases = U64(stats.purchases.0 + 1);
+        let volume = stats.volume.entry(ft_token_id.clone()).or_insert(U128(0));
+        volume.0 += price;
+        let earned = stats.earned.entry(ft_token_id.clone()).or_insert(U128(0));
+        earned.0 += referral_fee;
+        self.referral_stats.insert(referrer_id, &stats);
+    }
+}
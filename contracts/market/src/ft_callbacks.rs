@@ -18,51 +18,91 @@ impl FungibleTokenReceiver for Contract {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
+        if let Ok(MakeOfferArgs { offer }) = near_sdk::serde_json::from_str(&msg) {
+            assert!(amount.0 > 0, "Amount must be greater than 0");
+            self.internal_make_offer(
+                offer.nft_contract_id.into(),
+                offer.token_type,
+                offer.token_id,
+                offer.expires_at,
+                env::predecessor_account_id(),
+                amount.0,
+                sender_id,
+            );
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        if let Ok(SwapArgs { swap_id }) = near_sdk::serde_json::from_str(&msg) {
+            self.internal_pay_swap_top_up(
+                swap_id.0,
+                env::predecessor_account_id(),
+                amount.0,
+                sender_id,
+            );
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        if let Ok(BuyBundleArgs {
+            bundle,
+            referrer_id,
+        }) = near_sdk::serde_json::from_str(&msg)
+        {
+            self.internal_buy_bundle(
+                bundle,
+                env::predecessor_account_id(),
+                amount.0,
+                sender_id,
+                referrer_id.map(|referrer_id| referrer_id.into()),
+            );
+            return PromiseOrValue::Value(U128(0));
+        }
+
         let PurchaseArgs {
             nft_contract_id,
             token_id,
+            sealed_bid_hash,
+            expires_at,
+            accept_counter_offer,
+            referrer_id,
         } = near_sdk::serde_json::from_str(&msg).expect("Invalid PurchaseArgs");
 
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let mut sale = self
-            .sales
-            .get(&contract_and_token_id)
-            .expect("No sale in ft_on_transfer");
-
-        assert_ne!(sale.owner_id, sender_id, "Cannot buy your own sale.");
-
-        let ft_token_id = env::predecessor_account_id();
-        let price = *sale
-            .sale_conditions
-            .get(&ft_token_id)
-            .expect("Not for sale in that token type");
-
         assert!(amount.0 > 0, "Amount must be greater than 0");
+        let referrer_id: Option<AccountId> = referrer_id.map(|referrer_id| referrer_id.into());
 
-        if !sale.is_auction && amount == price {
-            self.process_purchase(
+        if accept_counter_offer.unwrap_or(false) {
+            self.internal_accept_counter_offer(
                 nft_contract_id.into(),
                 token_id,
-                ft_token_id,
-                price,
+                env::predecessor_account_id(),
+                amount.0,
                 sender_id,
-            )
-            .into()
-        } else {
-            if sale.is_auction && price.0 > 0 {
-                assert!(
-                    amount.0 >= price.0,
-                    "Amount must be greater than reserve price"
-                );
-            }
-            self.add_bid(
-                contract_and_token_id,
+            );
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        if let Some(sealed_bid_hash) = sealed_bid_hash {
+            self.internal_commit_bid(
+                nft_contract_id.into(),
+                token_id,
+                env::predecessor_account_id(),
                 amount.0,
-                ft_token_id,
                 sender_id,
-                &mut sale,
+                sealed_bid_hash,
+                referrer_id,
             );
-            PromiseOrValue::Value(U128(0))
+            return PromiseOrValue::Value(U128(0));
         }
+
+        // keep the FTs in escrow, refunds and failed purchases are paid back with ft_transfer
+        self.internal_offer(
+            nft_contract_id.into(),
+            token_id,
+            env::predecessor_account_id(),
+            amount.0,
+            sender_id,
+            expires_at,
+            referrer_id,
+        );
+        PromiseOrValue::Value(U128(0))
     }
 }
@@ -6,6 +6,51 @@ pub(crate) fn hash_account_id(account_id: &AccountId) -> CryptoHash {
     hash
 }
 
+/// block timestamp in milliseconds, the unit used by `Sale.created_at`
+pub(crate) fn current_time_ms() -> u64 {
+    env::block_timestamp() / 1_000_000
+}
+
+/// bps basis points of amount, rounded down, without overflowing
+pub(crate) fn basis_points_of(amount: Balance, bps: u16) -> Balance {
+    let bps = bps as u128;
+    amount / 10_000 * bps + amount % 10_000 * bps / 10_000
+}
+
+/// the payout returned by nft_transfer_payout, None unless it adds up to balance
+pub(crate) fn parse_payout(value: &[u8], balance: Balance) -> Option<Payout> {
+    let payout = near_sdk::serde_json::from_slice::<Payout>(value).ok()?;
+    if payout.is_empty() {
+        return None;
+    }
+    // TODO off by 1 e.g. payouts are fractions of 3333 + 3333 + 3333
+    let mut remainder = balance;
+    for &value in payout.values() {
+        remainder = remainder.checked_sub(value.0)?;
+    }
+    if remainder == 0 || remainder == 1 {
+        Some(payout)
+    } else {
+        None
+    }
+}
+
+/// send NEAR or FTs held by the market to receiver_id
+pub(crate) fn transfer_to(ft_token_id: &str, receiver_id: &str, amount: U128) {
+    if ft_token_id == "near" {
+        Promise::new(receiver_id.to_string()).transfer(amount.0);
+    } else {
+        ext_contract::ft_transfer(
+            receiver_id.to_string(),
+            amount,
+            None,
+            &ft_token_id.to_string(),
+            1,
+            GAS_FOR_FT_TRANSFER,
+        );
+    }
+}
+
 impl Contract {
     pub(crate) fn assert_owner(&self) {
         assert_eq!(
@@ -15,72 +60,708 @@ impl Contract {
         );
     }
 
+    /// enforce account_id's storage is enough to cover one more offer, bundle item or swap
+    pub(crate) fn assert_storage_for_one_more(&self, account_id: &AccountId) {
+        let paid_storage = self.storage_deposits.get(account_id).unwrap_or(0);
+        let storage_required = self.storage_used(account_id) + STORAGE_PER_SALE;
+        assert!(
+            paid_storage >= storage_required,
+            "Insufficient storage paid: {}, required: {}",
+            paid_storage,
+            storage_required
+        );
+    }
+
+    /// a referrer is only paid, and only counts as a payout receiver, if the market sets a share
+    pub(crate) fn referral_fee_bps(&self, referrer_id: Option<&AccountId>) -> u16 {
+        referrer_id.map_or(0, |_| self.referral_fee_bps)
+    }
+
+    /// pay the receivers of a purchase at price, protocol and referral fees come out of each
+    /// receiver's share and rounding dust goes to the treasury
+    pub(crate) fn internal_pay_out(
+        &mut self,
+        ft_token_id: &AccountId,
+        payout: Payout,
+        price: Balance,
+        referrer_id: Option<AccountId>,
+    ) -> PaidOut {
+        let paid_out = self.internal_paid_out(payout, price, referrer_id);
+        self.internal_transfer_paid_out(ft_token_id, &paid_out, price);
+        paid_out
+    }
+
+    /// where a payout at price goes once the fees come out of each receiver's share
+    pub(crate) fn internal_paid_out(
+        &self,
+        payout: Payout,
+        price: Balance,
+        referrer_id: Option<AccountId>,
+    ) -> PaidOut {
+        let referral_fee_bps = self.referral_fee_bps(referrer_id.as_ref());
+        let mut paid = 0;
+        let mut referral_fee = 0;
+        let mut paid_payout = Payout::new();
+        for (receiver_id, amount) in payout {
+            let referral_cut = basis_points_of(amount.0, referral_fee_bps);
+            let amount = amount.0 - basis_points_of(amount.0, self.protocol_fee_bps) - referral_cut;
+            referral_fee += referral_cut;
+            paid += amount;
+            paid_payout.insert(receiver_id, U128(amount));
+        }
+        PaidOut {
+            payout: paid_payout,
+            protocol_fee: U128(price - paid - referral_fee),
+            referrer_id,
+            referral_fee: U128(referral_fee),
+        }
+    }
+
+    /// send a purchase's paid_out and keep the protocol fee in the treasury
+    pub(crate) fn internal_transfer_paid_out(
+        &mut self,
+        ft_token_id: &AccountId,
+        paid_out: &PaidOut,
+        price: Balance,
+    ) {
+        for (receiver_id, amount) in &paid_out.payout {
+            transfer_to(ft_token_id, receiver_id, *amount);
+        }
+        if let Some(referrer_id) = &paid_out.referrer_id {
+            if paid_out.referral_fee.0 > 0 {
+                transfer_to(ft_token_id, referrer_id, paid_out.referral_fee);
+            }
+            self.internal_record_referral(referrer_id, ft_token_id, price, paid_out.referral_fee.0);
+        }
+        self.internal_accrue_protocol_fee(ft_token_id, paid_out.protocol_fee.0);
+    }
+
+    /// a new sale has to fit in the storage deposit of its owner
+    pub(crate) fn assert_storage_paid(&self, account_id: &AccountId) {
+        let paid_storage = self.storage_deposits.get(account_id).unwrap_or(0);
+        let storage_required = self.storage_used(account_id);
+        assert!(
+            paid_storage >= storage_required,
+            "Insufficient storage paid: {}, required: {}",
+            paid_storage,
+            storage_required
+        );
+    }
+
+    /// storage held for the sales, offers, bundle items and proposed swaps of account_id
+    pub(crate) fn storage_used(&self, account_id: &AccountId) -> Balance {
+        let sale_storage = self.sale_storage_by_owner_id.get(account_id).unwrap_or(0);
+        let offers = self
+            .offers_by_owner_id
+            .get(account_id)
+            .map_or(0, |s| s.len());
+        let bundle_items: u64 = self.bundles_by_owner_id.get(account_id).map_or(0, |s| {
+            s.iter()
+                .map(|key| self.bundles.get(&key).map_or(0, |b| b.items.len() as u64))
+                .sum()
+        });
+        let swaps = self.swaps_by_account_id.get(account_id).map_or(0, |s| {
+            s.iter()
+                .filter(|swap_id| {
+                    self.swaps
+                        .get(swap_id)
+                        .map_or(false, |swap| &swap.maker_id == account_id)
+                })
+                .count() as u64
+        });
+        Balance::from(sale_storage) * STORAGE_PRICE_PER_BYTE
+            + u128::from(offers + bundle_items + swaps) * STORAGE_PER_SALE
+    }
+
+    /// what of total can be withdrawn, the minimum balance stays until unregistering
+    pub(crate) fn storage_available(&self, account_id: &AccountId, total: Balance) -> Balance {
+        total.saturating_sub(max(self.storage_used(account_id), STORAGE_PER_SALE))
+    }
+
+    /// a new approval of a listed token makes the listing unbuyable, remove it and refund its bids
+    pub(crate) fn internal_remove_stale_sale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if self.sales.get(&contract_and_token_id).is_some() {
+            let sale = self.internal_remove_sale(nft_contract_id, token_id);
+            self.refund_all_bids(&contract_and_token_id, &sale.bids);
+            self.refund_sealed_bids(&sale);
+        }
+    }
+
+    /// transfer a sale that has already been removed to buyer_id for price, the seller's
+    /// deposit holds the trade's storage until resolve_purchase
+    pub(crate) fn internal_nft_transfer_payout(
+        &mut self,
+        sale: Sale,
+        ft_token_id: AccountId,
+        price: U128,
+        buyer_id: AccountId,
+        referrer_id: Option<AccountId>,
+    ) -> Promise {
+        self.internal_hold_storage(&sale.owner_id, sale.trade_storage_max());
+        ext_contract::nft_transfer_payout(
+            buyer_id.clone(),
+            sale.token_id.clone(),
+            sale.approval_id,
+            None,
+            price,
+            &sale.nft_contract_id,
+            1,
+            GAS_FOR_NFT_TRANSFER,
+        )
+        .then(ext_self::resolve_purchase(
+            ft_token_id,
+            buyer_id,
+            sale,
+            price,
+            referrer_id,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ROYALTIES,
+        ))
+    }
+
     /// refund the last bid of each token type, don't update sale because it's already been removed
 
-    pub(crate) fn refund_all_bids(&mut self, bids: &Bids) {
+    pub(crate) fn refund_all_bids(&mut self, contract_and_token_id: &str, bids: &Bids) {
         for (bid_ft, bid_vec) in bids {
             let bid = &bid_vec[bid_vec.len() - 1];
-            if bid_ft == "near" {
-                Promise::new(bid.owner_id.clone()).transfer(u128::from(bid.price));
+            transfer_to(bid_ft, &bid.owner_id, bid.price);
+            MarketEvent::BidRefunded(vec![BidData::new(
+                contract_and_token_id,
+                &bid.owner_id,
+                bid_ft,
+                bid.price,
+            )])
+            .emit();
+            self.internal_remove_bid_index(&bid.owner_id, contract_and_token_id, bid_ft);
+        }
+    }
+
+    /// by_bidder_id tracks the escrowed bids of each account as "contract||token||ft"
+    pub(crate) fn internal_add_bid_index(
+        &mut self,
+        bidder_id: &AccountId,
+        contract_and_token_id: &str,
+        ft_token_id: &str,
+    ) {
+        let mut by_bidder_id = self.by_bidder_id.get(bidder_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByBidderIdInner {
+                    account_id_hash: hash_account_id(bidder_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+        by_bidder_id.insert(&format!(
+            "{}{}{}",
+            contract_and_token_id, DELIMETER, ft_token_id
+        ));
+        self.by_bidder_id.insert(bidder_id, &by_bidder_id);
+    }
+
+    pub(crate) fn internal_remove_bid_index(
+        &mut self,
+        bidder_id: &AccountId,
+        contract_and_token_id: &str,
+        ft_token_id: &str,
+    ) {
+        if let Some(mut by_bidder_id) = self.by_bidder_id.get(bidder_id) {
+            by_bidder_id.remove(&format!(
+                "{}{}{}",
+                contract_and_token_id, DELIMETER, ft_token_id
+            ));
+            if by_bidder_id.is_empty() {
+                self.by_bidder_id.remove(bidder_id);
             } else {
-                ext_contract::ft_transfer(
-                    bid.owner_id.clone(),
-                    bid.price,
-                    None,
-                    bid_ft,
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
+                self.by_bidder_id.insert(bidder_id, &by_bidder_id);
             }
         }
     }
 
-    pub(crate) fn internal_remove_sale(
+    /// remove everything account_id holds storage for, refunding bids, offers and top-ups
+    pub(crate) fn internal_remove_account_listings(&mut self, account_id: &AccountId) {
+        let sale_keys: Vec<ContractAndTokenId> =
+            self.by_owner_id.get(account_id).map_or(vec![], |listings| {
+                listings
+                    .iter()
+                    .map(|((_, contract_and_token_id), _)| contract_and_token_id)
+                    .collect()
+            });
+        for contract_and_token_id in sale_keys {
+            let sale = self.sales.get(&contract_and_token_id).unwrap();
+            let sale = self.internal_remove_sale(sale.nft_contract_id, sale.token_id);
+            self.refund_all_bids(&contract_and_token_id, &sale.bids);
+            self.refund_sealed_bids(&sale);
+        }
+
+        let offer_ids = self
+            .offers_by_owner_id
+            .get(account_id)
+            .map_or(vec![], |offer_ids| offer_ids.to_vec());
+        for offer_id in offer_ids {
+            let offer = self.internal_remove_offer(offer_id);
+            transfer_to(&offer.ft_token_id, &offer.owner_id, offer.price);
+        }
+
+        let bundle_keys = self
+            .bundles_by_owner_id
+            .get(account_id)
+            .map_or(vec![], |bundle_keys| bundle_keys.to_vec());
+        for owner_and_bundle_id in bundle_keys {
+            self.internal_remove_bundle(&owner_and_bundle_id);
+        }
+
+        let swap_ids = self
+            .swaps_by_account_id
+            .get(account_id)
+            .map_or(vec![], |swap_ids| swap_ids.to_vec());
+        for swap_id in swap_ids {
+            if &self.swaps.get(&swap_id).unwrap().maker_id != account_id {
+                continue;
+            }
+            let swap = self.internal_remove_swap(swap_id);
+            if let Some(top_up) = swap.top_up.filter(|top_up| top_up.is_paid) {
+                transfer_to(&top_up.ft_token_id, &top_up.payer_id, top_up.amount);
+            }
+        }
+    }
+
+    /// take bytes out of account_id's storage deposit until a purchase resolves. The deposit
+    /// must cover them on top of what it still holds for
+    pub(crate) fn internal_hold_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        let cost = Balance::from(bytes) * STORAGE_PRICE_PER_BYTE;
+        let balance = self.storage_deposits.get(account_id).unwrap_or(0);
+        assert!(
+            self.storage_used(account_id) + cost <= balance,
+            "Seller's storage deposit can't cover the trade, required: {}",
+            cost
+        );
+        self.storage_deposits.insert(account_id, &(balance - cost));
+    }
+
+    /// pay for the bytes written since initial_storage_usage out of held_bytes, trade history
+    /// is kept for good so whoever sold pays for it. The rest goes back to account_id
+    pub(crate) fn internal_settle_held_storage(
+        &mut self,
+        account_id: &AccountId,
+        held_bytes: StorageUsage,
+        initial_storage_usage: StorageUsage,
+    ) {
+        let used = env::storage_usage().saturating_sub(initial_storage_usage);
+        let unused = Balance::from(held_bytes.saturating_sub(used)) * STORAGE_PRICE_PER_BYTE;
+        if unused == 0 {
+            return;
+        }
+        if let Some(balance) = self.storage_deposits.get(account_id) {
+            self.storage_deposits
+                .insert(account_id, &(balance + unused));
+        } else {
+            Promise::new(account_id.clone()).transfer(unused);
+        }
+    }
+
+    /// refund every sealed bid deposit of a sale that has already been removed
+    pub(crate) fn refund_sealed_bids(&mut self, sale: &Sale) {
+        if let Some(sealed_bid) = &sale.sealed_bid {
+            let contract_and_token_id = sale.contract_and_token_id();
+            let ft_token_id = sale.sale_conditions.keys().next().unwrap();
+            for commitment in &sealed_bid.commitments {
+                transfer_to(ft_token_id, &commitment.owner_id, commitment.deposit);
+                MarketEvent::BidRefunded(vec![BidData::new(
+                    &contract_and_token_id,
+                    &commitment.owner_id,
+                    ft_token_id,
+                    commitment.deposit,
+                )])
+                .emit();
+            }
+        }
+    }
+
+    /// buy or bid on a sale with amount of ft_token_id the market already holds, see
+    /// offer for NEAR and ft_on_transfer for FTs
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn internal_offer(
         &mut self,
         nft_contract_id: AccountId,
         token_id: TokenId,
-    ) -> Sale {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let sale = self.sales.remove(&contract_and_token_id).expect("No sale");
+        ft_token_id: AccountId,
+        amount: Balance,
+        buyer_id: AccountId,
+        bid_expires_at: Option<U64>,
+        referrer_id: Option<AccountId>,
+    ) {
+        let referrer_id = referrer_id.filter(|referrer_id| referrer_id != &buyer_id);
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        // offers on unlisted tokens go through make_offer, which returns their offer_id
+        let mut sale = self
+            .sales
+            .get(&contract_and_token_id)
+            .expect("No sale, use make_offer for unlisted tokens");
+        assert_ne!(sale.owner_id, buyer_id, "Cannot buy your own sale.");
+        assert!(sale.is_buyer_allowed(&buyer_id), "Private sale");
+        sale.assert_listed(current_time_ms());
+        assert!(
+            sale.sealed_bid.is_none(),
+            "Sealed-bid auctions only take committed bids"
+        );
+        let price = sale
+            .current_price(&ft_token_id, current_time_ms())
+            .unwrap_or_else(|| env::panic(format!("Not for sale in {}", ft_token_id).as_bytes()));
 
-        let mut by_owner_id = self
-            .by_owner_id
-            .get(&sale.owner_id)
-            .expect("No sale by_owner_id");
-        by_owner_id.remove(&contract_and_token_id);
-        if by_owner_id.is_empty() {
-            self.by_owner_id.remove(&sale.owner_id);
+        let buy_now_price = sale
+            .buy_now_price(&ft_token_id)
+            .filter(|buy_now_price| amount >= *buy_now_price);
+        let purchase_price = if sale.dutch_auction.is_some() {
+            assert!(
+                amount >= price,
+                "Amount {} is less than the current price {}",
+                amount,
+                price
+            );
+            Some(price)
+        } else if let Some(buy_now_price) = buy_now_price {
+            sale.assert_auction_open(current_time_ms());
+            Some(buy_now_price)
+        } else if !sale.is_auction && amount == price {
+            Some(price)
         } else {
-            self.by_owner_id.insert(&sale.owner_id, &by_owner_id);
-        }
+            None
+        };
 
-        let mut by_nft_contract_id = self
-            .by_nft_contract_id
-            .get(&nft_contract_id)
-            .expect("No sale by nft_contract_id");
-        by_nft_contract_id.remove(&token_id);
-        if by_nft_contract_id.is_empty() {
-            self.by_nft_contract_id.remove(&nft_contract_id);
+        if let Some(purchase_price) = purchase_price {
+            if amount > purchase_price {
+                transfer_to(&ft_token_id, &buyer_id, U128(amount - purchase_price));
+            }
+            self.process_purchase(
+                nft_contract_id,
+                token_id,
+                ft_token_id,
+                U128(purchase_price),
+                buyer_id,
+                referrer_id,
+            );
         } else {
-            self.by_nft_contract_id
-                .insert(&nft_contract_id, &by_nft_contract_id);
-        }
-
-        let token_type = sale.token_type.clone();
-        if let Some(token_type) = token_type {
-            let mut by_nft_token_type = self
-                .by_nft_token_type
-                .get(&token_type)
-                .expect("No sale by nft_token_type");
-            by_nft_token_type.remove(&contract_and_token_id);
-            if by_nft_token_type.is_empty() {
-                self.by_nft_token_type.remove(&token_type);
-            } else {
-                self.by_nft_token_type
-                    .insert(&token_type, &by_nft_token_type);
+            self.add_bid(
+                contract_and_token_id,
+                amount,
+                ft_token_id,
+                buyer_id,
+                bid_expires_at,
+                referrer_id,
+                &mut sale,
+            );
+        }
+    }
+
+    /// buy at the counter-offer price with the escrowed bid plus amount
+    pub(crate) fn internal_accept_counter_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        amount: Balance,
+        buyer_id: AccountId,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        let counter_offer = sale
+            .counter_offers
+            .remove(&ft_token_id)
+            .expect("No counter-offer");
+        assert_eq!(
+            counter_offer.bidder_id, buyer_id,
+            "Counter-offer is for another bidder"
+        );
+        let now = current_time_ms();
+        sale.assert_listed(now);
+        assert!(!counter_offer.is_expired(now), "Counter-offer has expired");
+        let bids_for_token_id = sale.bids.remove(&ft_token_id).expect("No bids");
+        let bid = &bids_for_token_id[bids_for_token_id.len() - 1];
+        assert_eq!(
+            bid.owner_id, buyer_id,
+            "Counter-offer is for another bidder"
+        );
+        assert!(!bid.is_expired(now), "Bid has expired");
+
+        let price = counter_offer.price.0;
+        let total = bid.price.0 + amount;
+        assert!(
+            total >= price,
+            "Counter-offer is {}, bid and top-up are {}",
+            price,
+            total
+        );
+        if total > price {
+            transfer_to(&ft_token_id, &buyer_id, U128(total - price));
+        }
+        self.internal_remove_bid_index(&buyer_id, &contract_and_token_id, &ft_token_id);
+        self.sales.insert(&contract_and_token_id, &sale);
+        self.process_purchase(
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            U128(price),
+            buyer_id,
+            bid.referrer_id.clone(),
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn internal_commit_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        amount: Balance,
+        bidder_id: AccountId,
+        hash: Base64VecU8,
+        referrer_id: Option<AccountId>,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        assert_ne!(sale.owner_id, bidder_id, "Cannot bid on your own sale.");
+        assert!(sale.is_buyer_allowed(&bidder_id), "Private sale");
+        sale.assert_listed(current_time_ms());
+        assert_eq!(
+            sale.sealed_bid_phase(current_time_ms()),
+            Some(SealedBidPhase::Commit),
+            "Not in the commit phase"
+        );
+        let reserve_price = sale
+            .sale_conditions
+            .get(&ft_token_id)
+            .unwrap_or_else(|| env::panic(format!("Not for sale in {}", ft_token_id).as_bytes()))
+            .0;
+        assert!(
+            amount >= reserve_price,
+            "Deposit must cover the reserve price"
+        );
+        assert_eq!(hash.0.len(), 32, "Hash must be a sha256 hash");
+        let sealed_bid = sale.sealed_bid.as_mut().unwrap();
+        assert!(
+            sealed_bid
+                .commitments
+                .iter()
+                .all(|commitment| commitment.owner_id != bidder_id),
+            "Already committed a bid"
+        );
+        let referrer_id = referrer_id.filter(|referrer_id| referrer_id != &bidder_id);
+        MarketEvent::BidPlaced(vec![BidData::new(
+            &contract_and_token_id,
+            &bidder_id,
+            &ft_token_id,
+            U128(amount),
+        )])
+        .emit();
+        sealed_bid.commitments.push(SealedBid {
+            owner_id: bidder_id,
+            hash,
+            deposit: U128(amount),
+            price: None,
+            referrer_id,
+        });
+        self.sales.insert(&contract_and_token_id, &sale);
+    }
+
+    /// the highest revealed bid buys the token, earliest commitment wins ties. Other revealed
+    /// deposits are refunded in full, a bid that was never revealed forfeits
+    /// UNREVEALED_BID_PENALTY_BPS of its deposit to the treasury, so that bidders can't commit
+    /// from several accounts and only reveal the bid that suits them
+    pub(crate) fn internal_settle_sealed_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        mut sale: Sale,
+    ) {
+        assert_eq!(
+            sale.sealed_bid_phase(current_time_ms()),
+            Some(SealedBidPhase::Ended),
+            "Reveal phase has not ended"
+        );
+        let ft_token_id = sale.sale_conditions.keys().next().cloned().unwrap();
+        let contract_and_token_id = sale.contract_and_token_id();
+        let commitments = std::mem::take(&mut sale.sealed_bid.as_mut().unwrap().commitments);
+        let mut winner: Option<&SealedBid> = None;
+        for commitment in &commitments {
+            if let Some(price) = commitment.price {
+                if winner.map_or(true, |winner| price.0 > winner.price.unwrap().0) {
+                    winner = Some(commitment);
+                }
+            }
+        }
+        for commitment in &commitments {
+            let refund = match winner {
+                Some(winner) if winner.owner_id == commitment.owner_id => {
+                    commitment.deposit.0 - winner.price.unwrap().0
+                }
+                _ if commitment.price.is_none() => {
+                    let penalty = basis_points_of(commitment.deposit.0, UNREVEALED_BID_PENALTY_BPS);
+                    self.internal_accrue_protocol_fee(&ft_token_id, penalty);
+                    commitment.deposit.0 - penalty
+                }
+                _ => commitment.deposit.0,
+            };
+            if refund > 0 {
+                transfer_to(&ft_token_id, &commitment.owner_id, U128(refund));
+                MarketEvent::BidRefunded(vec![BidData::new(
+                    &contract_and_token_id,
+                    &commitment.owner_id,
+                    &ft_token_id,
+                    U128(refund),
+                )])
+                .emit();
             }
         }
 
+        if let Some(winner) = winner {
+            self.sales.insert(&contract_and_token_id, &sale);
+            self.process_purchase(
+                nft_contract_id,
+                token_id,
+                ft_token_id,
+                winner.price.unwrap(),
+                winner.owner_id.clone(),
+                winner.referrer_id.clone(),
+            );
+        } else {
+            let sale = self.internal_remove_sale(nft_contract_id, token_id);
+            self.refund_all_bids(&sale.contract_and_token_id(), &sale.bids);
+        }
+    }
+
+    /// an auction's sale_conditions are the opening bid, later bids must raise the current
+    /// bid by the sale's or else the market's min_bid_increment. An expired bid doesn't count
+    pub(crate) fn internal_min_next_bid(&self, sale: &Sale, ft_token_id: &str) -> Balance {
+        let now = current_time_ms();
+        let current_bid = sale
+            .bids
+            .get(ft_token_id)
+            .and_then(|bids| bids.last())
+            .filter(|bid| !bid.is_expired(now))
+            .map(|bid| bid.price.0);
+        if let Some(current_bid) = current_bid {
+            sale.min_bid_increment
+                .unwrap_or(self.min_bid_increment)
+                .next_bid(current_bid)
+        } else if sale.is_auction {
+            let opening_price = sale.sale_conditions.get(ft_token_id).map_or(0, |p| p.0);
+            std::cmp::max(opening_price, 1)
+        } else {
+            1
+        }
+    }
+
+    /// index a new sale by created_at, globally and by owner, collection and token type
+    pub(crate) fn internal_add_listing(&mut self, sale: &Sale) {
+        self.internal_update_listing(sale, true);
+    }
+
+    pub(crate) fn internal_remove_listing(&mut self, sale: &Sale) {
+        self.internal_update_listing(sale, false);
+    }
+
+    fn internal_update_listing(&mut self, sale: &Sale, is_insert: bool) {
+        let entry = (sale.created_at.0, sale.contract_and_token_id());
+        if is_insert {
+            self.listings.insert(&entry, &());
+        } else {
+            self.listings.remove(&entry);
+        }
+        update_listing_index(
+            &mut self.by_owner_id,
+            StorageKey::ByOwnerIdInner {
+                account_id_hash: hash_account_id(&sale.owner_id),
+            },
+            &sale.owner_id,
+            &entry,
+            is_insert,
+        );
+        update_listing_index(
+            &mut self.by_nft_contract_id,
+            StorageKey::ByNFTContractIdInner {
+                account_id_hash: hash_account_id(&sale.nft_contract_id),
+            },
+            &sale.nft_contract_id,
+            &entry,
+            is_insert,
+        );
+        if let Some(token_type) = &sale.token_type {
+            update_listing_index(
+                &mut self.by_nft_token_type,
+                StorageKey::ByNFTTokenTypeInner {
+                    token_type_hash: hash_account_id(token_type),
+                },
+                token_type,
+                &entry,
+                is_insert,
+            );
+        }
+    }
+
+    /// the bytes a sale was charged at listing become available to its owner again
+    pub(crate) fn internal_release_sale_storage(&mut self, sale: &Sale) {
+        let sale_storage = self
+            .sale_storage_by_owner_id
+            .get(&sale.owner_id)
+            .unwrap_or(0)
+            .saturating_sub(sale.storage_usage.0);
+        if sale_storage == 0 {
+            self.sale_storage_by_owner_id.remove(&sale.owner_id);
+        } else {
+            self.sale_storage_by_owner_id
+                .insert(&sale.owner_id, &sale_storage);
+        }
+    }
+
+    /// every way a sale ends comes through here
+    pub(crate) fn internal_remove_sale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Sale {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let sale = self.sales.remove(&contract_and_token_id).expect("No sale");
+        self.internal_remove_listing(&sale);
+        self.internal_release_sale_storage(&sale);
+        self.internal_remove_sale_prices(&sale);
+        MarketEvent::SaleRemoved(vec![SaleRemovedData {
+            owner_id: &sale.owner_id,
+            nft_contract_id: &nft_contract_id,
+            token_id: &token_id,
+        }])
+        .emit();
+
         sale
     }
 }
+
+fn update_listing_index(
+    indexes: &mut LookupMap<String, ListingIndex>,
+    storage_key: StorageKey,
+    group: &str,
+    entry: &(u64, ContractAndTokenId),
+    is_insert: bool,
+) {
+    let group = group.to_string();
+    let mut index = indexes
+        .get(&group)
+        .unwrap_or_else(|| TreeMap::new(storage_key.try_to_vec().unwrap()));
+    if is_insert {
+        index.insert(entry, &());
+    } else {
+        index.remove(entry);
+    }
+    if index.len() == 0 {
+        indexes.remove(&group);
+    } else {
+        indexes.insert(&group, &index);
+    }
+}
@@ -1,25 +1,47 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
-use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::collections::{LookupMap, LookupSet, TreeMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::{Base64VecU8, ValidAccountId, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     assert_one_yocto, env, ext_contract, near_bindgen, AccountId, Balance, BorshStorageKey,
-    CryptoHash, Gas, PanicOnDefault, Promise, PromiseOrValue,
+    CryptoHash, Gas, PanicOnDefault, Promise, PromiseOrValue, PromiseResult, StorageUsage,
 };
-use std::cmp::min;
-use std::collections::HashMap;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
 
+use crate::bundle::*;
+use crate::events::*;
 use crate::external::*;
+use crate::fees::*;
 use crate::internal::*;
+use crate::offer::*;
+use crate::price_index::*;
 use crate::sale::*;
+use crate::stats::*;
+use crate::swap::*;
+use crate::trade::*;
 use near_sdk::env::STORAGE_PRICE_PER_BYTE;
 
+mod bundle;
+mod bundle_views;
+mod events;
 mod external;
+mod fees;
 mod ft_callbacks;
 mod internal;
 mod nft_callbacks;
+mod offer;
+mod offer_views;
+mod price_index;
+mod price_index_views;
 mod sale;
 mod sale_views;
+mod stats;
+mod swap;
+mod swap_views;
+mod trade;
+mod trade_views;
+mod verify;
 
 near_sdk::setup_alloc!();
 
@@ -27,18 +49,54 @@ const GAS_FOR_FT_TRANSFER: Gas = 5_000_000_000_000;
 /// greedy max Tgas for resolve_purchase
 const GAS_FOR_ROYALTIES: Gas = 115_000_000_000_000;
 const GAS_FOR_NFT_TRANSFER: Gas = 15_000_000_000_000;
+/// resolve_swap without the transfers it makes
+const GAS_FOR_RESOLVE_SWAP: Gas = 20_000_000_000_000;
+const GAS_FOR_NFT_VIEW: Gas = 10_000_000_000_000;
+const GAS_FOR_RESOLVE_WITHDRAW: Gas = 10_000_000_000_000;
+/// removing an invalid sale refunds its bids and the owner's storage
+const GAS_FOR_RESOLVE_VERIFY_SALE: Gas = 40_000_000_000_000;
+/// verify_sale with the calls it makes, verify_sales needs this much per sale
+const GAS_FOR_VERIFY_SALE: Gas =
+    2 * GAS_FOR_NFT_VIEW + GAS_FOR_RESOLVE_VERIFY_SALE + 10_000_000_000_000;
+/// share of a sealed bid's deposit forfeited if it isn't revealed, in basis points
+const UNREVEALED_BID_PENALTY_BPS: u16 = 1_000;
 const BID_HISTORY_LENGTH_DEFAULT: u8 = 1;
+/// bids can't be withdrawn this close to a timed auction's end, unless the sale sets an extension_window
+const AUCTION_FINAL_WINDOW: u64 = 10 * 60 * 1000;
+const MIN_BID_INCREMENT_DEFAULT: BidIncrement = BidIncrement::Absolute(U128(1));
+const PROTOCOL_FEE_BPS_DEFAULT: u16 = 0;
+const REFERRAL_FEE_BPS_DEFAULT: u16 = 0;
+/// gas to move every item of a bundle to the market and on to the buyer fits in one purchase
+const BUNDLE_SIZE_MAX: u8 = 4;
+/// gas to move every token of a swap to the market and on fits in one approval
+const SWAP_SIZE_MAX: u8 = 4;
+/// sales get_sales and the listing views look at for one page
+const SALE_QUERY_SCAN_MAX: u64 = 500;
 const NO_DEPOSIT: Balance = 0;
-const STORAGE_PER_SALE: u128 = 1000 * STORAGE_PRICE_PER_BYTE;
+/// the least a registration takes and what each offer, bundle item and swap holds. Sales hold
+/// the bytes they took and the most their trade can take. Measured with short ids an owner's
+/// first sale lists in about 1,750 bytes and reserves about 2,250 for its trade, offers, bundle
+/// items and swaps take under 2,000 even with 64 character ids
+const STORAGE_PER_SALE: u128 = 4_000 * STORAGE_PRICE_PER_BYTE;
 static DELIMETER: &str = "||";
 
 pub type SaleConditions = HashMap<FungibleTokenId, U128>;
+/// the last bid_history_length bids per token, oldest first. Only the last bid is escrowed,
+/// the others were refunded when outbid, and the history goes when the last bid leaves
 pub type Bids = HashMap<FungibleTokenId, Vec<Bid>>;
 pub type TokenId = String;
 pub type TokenType = Option<String>;
 pub type FungibleTokenId = AccountId;
 pub type ContractAndTokenId = String;
+pub type OfferId = u64;
+pub type OwnerAndBundleId = String;
+pub type SwapId = u64;
+pub type TradeId = u64;
 pub type Payout = HashMap<AccountId, U128>;
+/// sales ordered by created_at, then sale key
+pub type ListingIndex = TreeMap<(u64, ContractAndTokenId), ()>;
+/// created_at and key of the last sale of a page, the next page starts after it
+pub type ListingCursor = (U64, ContractAndTokenId);
 
 #[derive(Serialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -47,17 +105,57 @@ pub struct StorageBalanceBounds {
     pub max: Option<U128>,
 }
 
+/// available is what isn't held for the account's sales, offers, bundle items and swaps, nor
+/// needed for the minimum balance
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
     pub owner_id: AccountId,
     pub sales: UnorderedMap<ContractAndTokenId, Sale>,
-    pub by_owner_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
-    pub by_nft_contract_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
-    pub by_nft_token_type: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
+    /// bytes the sales of an owner took
+    pub sale_storage_by_owner_id: LookupMap<AccountId, StorageUsage>,
+    /// every sale in listing order, the by_ indexes hold the sales of one group
+    pub listings: ListingIndex,
+    pub by_owner_id: LookupMap<AccountId, ListingIndex>,
+    pub by_nft_contract_id: LookupMap<AccountId, ListingIndex>,
+    pub by_nft_token_type: LookupMap<AccountId, ListingIndex>,
     pub ft_token_ids: UnorderedSet<AccountId>,
     pub storage_deposits: LookupMap<AccountId, Balance>,
     pub bid_history_length: u8,
+    pub min_bid_increment: BidIncrement,
+    pub by_bidder_id: LookupMap<AccountId, UnorderedSet<String>>,
+    pub offers: UnorderedMap<OfferId, Offer>,
+    pub next_offer_id: OfferId,
+    pub offers_by_owner_id: LookupMap<AccountId, UnorderedSet<OfferId>>,
+    pub offers_by_nft_contract_id: LookupMap<AccountId, UnorderedSet<OfferId>>,
+    pub offers_by_nft_token_type: LookupMap<String, UnorderedSet<OfferId>>,
+    pub offers_by_token: LookupMap<ContractAndTokenId, UnorderedSet<OfferId>>,
+    pub protocol_fee_bps: u16,
+    pub protocol_fees: LookupMap<FungibleTokenId, ProtocolFees>,
+    pub referral_fee_bps: u16,
+    pub referral_stats: LookupMap<AccountId, ReferralStats>,
+    pub bundles: UnorderedMap<OwnerAndBundleId, Bundle>,
+    pub bundles_by_owner_id: LookupMap<AccountId, UnorderedSet<OwnerAndBundleId>>,
+    pub swaps: UnorderedMap<SwapId, Swap>,
+    pub next_swap_id: SwapId,
+    pub swaps_by_account_id: LookupMap<AccountId, UnorderedSet<SwapId>>,
+    pub trades: Vector<Trade>,
+    pub trades_by_token: LookupMap<ContractAndTokenId, Vector<TradeId>>,
+    pub trades_by_account_id: LookupMap<AccountId, Vector<TradeId>>,
+    pub trades_by_nft_contract_id: LookupMap<AccountId, Vector<TradeId>>,
+    /// keyed by "nft_contract_id||ft_token_id" and "token_type||ft_token_id"
+    pub prices_by_nft_contract_id: LookupMap<String, PriceIndex>,
+    pub prices_by_nft_token_type: LookupMap<String, PriceIndex>,
+    pub stats_by_nft_contract_id: LookupMap<AccountId, MarketStats>,
+    pub stats_by_nft_token_type: LookupMap<String, MarketStats>,
+    pub stats_traders: LookupSet<String>,
 }
 
 #[derive(BorshStorageKey, BorshSerialize)]
@@ -71,6 +169,41 @@ pub enum StorageKey {
     ByNFTTokenTypeInner { token_type_hash: CryptoHash },
     FTTokenIds,
     StorageDeposits,
+    ByBidderId,
+    ByBidderIdInner { account_id_hash: CryptoHash },
+    Offers,
+    OffersByOwnerId,
+    OffersByOwnerIdInner { account_id_hash: CryptoHash },
+    OffersByNFTContractId,
+    OffersByNFTContractIdInner { account_id_hash: CryptoHash },
+    OffersByNFTTokenType,
+    OffersByNFTTokenTypeInner { token_type_hash: CryptoHash },
+    OffersByToken,
+    OffersByTokenInner { token_hash: CryptoHash },
+    ProtocolFees,
+    ReferralStats,
+    Bundles,
+    BundlesByOwnerId,
+    BundlesByOwnerIdInner { account_id_hash: CryptoHash },
+    Swaps,
+    SwapsByAccountId,
+    SwapsByAccountIdInner { account_id_hash: CryptoHash },
+    Trades,
+    TradesByToken,
+    TradesByTokenInner { token_hash: CryptoHash },
+    TradesByAccountId,
+    TradesByAccountIdInner { account_id_hash: CryptoHash },
+    TradesByNFTContractId,
+    TradesByNFTContractIdInner { account_id_hash: CryptoHash },
+    PricesByNFTContractId,
+    PricesByNFTContractIdInner { index_hash: CryptoHash },
+    PricesByNFTTokenType,
+    PricesByNFTTokenTypeInner { index_hash: CryptoHash },
+    StatsByNFTContractId,
+    StatsByNFTTokenType,
+    StatsTraders,
+    Listings,
+    SaleStorageByOwnerId,
 }
 
 #[near_bindgen]
@@ -84,12 +217,40 @@ impl Contract {
         let mut this = Self {
             owner_id: owner_id.into(),
             sales: UnorderedMap::new(StorageKey::Sales),
+            sale_storage_by_owner_id: LookupMap::new(StorageKey::SaleStorageByOwnerId),
+            listings: TreeMap::new(StorageKey::Listings),
             by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
             by_nft_contract_id: LookupMap::new(StorageKey::ByNFTContractId),
             by_nft_token_type: LookupMap::new(StorageKey::ByNFTTokenType),
             ft_token_ids: UnorderedSet::new(StorageKey::FTTokenIds),
             storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
             bid_history_length: bid_history_length.unwrap_or(BID_HISTORY_LENGTH_DEFAULT),
+            min_bid_increment: MIN_BID_INCREMENT_DEFAULT,
+            by_bidder_id: LookupMap::new(StorageKey::ByBidderId),
+            offers: UnorderedMap::new(StorageKey::Offers),
+            next_offer_id: 0,
+            offers_by_owner_id: LookupMap::new(StorageKey::OffersByOwnerId),
+            offers_by_nft_contract_id: LookupMap::new(StorageKey::OffersByNFTContractId),
+            offers_by_nft_token_type: LookupMap::new(StorageKey::OffersByNFTTokenType),
+            offers_by_token: LookupMap::new(StorageKey::OffersByToken),
+            protocol_fee_bps: PROTOCOL_FEE_BPS_DEFAULT,
+            protocol_fees: LookupMap::new(StorageKey::ProtocolFees),
+            referral_fee_bps: REFERRAL_FEE_BPS_DEFAULT,
+            referral_stats: LookupMap::new(StorageKey::ReferralStats),
+            bundles: UnorderedMap::new(StorageKey::Bundles),
+            bundles_by_owner_id: LookupMap::new(StorageKey::BundlesByOwnerId),
+            swaps: UnorderedMap::new(StorageKey::Swaps),
+            next_swap_id: 0,
+            swaps_by_account_id: LookupMap::new(StorageKey::SwapsByAccountId),
+            trades: Vector::new(StorageKey::Trades),
+            trades_by_token: LookupMap::new(StorageKey::TradesByToken),
+            trades_by_account_id: LookupMap::new(StorageKey::TradesByAccountId),
+            trades_by_nft_contract_id: LookupMap::new(StorageKey::TradesByNFTContractId),
+            prices_by_nft_contract_id: LookupMap::new(StorageKey::PricesByNFTContractId),
+            prices_by_nft_token_type: LookupMap::new(StorageKey::PricesByNFTTokenType),
+            stats_by_nft_contract_id: LookupMap::new(StorageKey::StatsByNFTContractId),
+            stats_by_nft_token_type: LookupMap::new(StorageKey::StatsByNFTTokenType),
+            stats_traders: LookupSet::new(StorageKey::StatsTraders),
         };
         // support NEAR by default
         this.ft_token_ids.insert(&"near".to_string());
@@ -113,44 +274,109 @@ impl Contract {
         added
     }
 
+    /// default for sales without their own min_bid_increment
+    pub fn set_min_bid_increment(&mut self, min_bid_increment: BidIncrement) {
+        self.assert_owner();
+        self.min_bid_increment = min_bid_increment;
+    }
+
+    /// NEP-145. Registering takes at least the minimum balance, with registration_only the
+    /// rest of the deposit is refunded
     #[payable]
-    pub fn storage_deposit(&mut self, account_id: Option<ValidAccountId>) {
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<ValidAccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
         let storage_account_id = account_id
             .map(|a| a.into())
             .unwrap_or_else(env::predecessor_account_id);
-        let deposit = env::attached_deposit();
-        assert!(
-            deposit >= STORAGE_PER_SALE,
-            "Requires minimum deposit of {}",
-            STORAGE_PER_SALE
-        );
-        let mut balance: u128 = self.storage_deposits.get(&storage_account_id).unwrap_or(0);
-        balance += deposit;
+        let mut deposit = env::attached_deposit();
+        let balance = self.storage_deposits.get(&storage_account_id);
+        if balance.is_none() {
+            assert!(
+                deposit >= STORAGE_PER_SALE,
+                "Requires minimum deposit of {}",
+                STORAGE_PER_SALE
+            );
+        }
+        if registration_only.unwrap_or(false) {
+            let registration = if balance.is_some() {
+                0
+            } else {
+                STORAGE_PER_SALE
+            };
+            if deposit > registration {
+                Promise::new(env::predecessor_account_id()).transfer(deposit - registration);
+            }
+            deposit = registration;
+        }
+        let balance = balance.unwrap_or(0) + deposit;
         self.storage_deposits.insert(&storage_account_id, &balance);
+        StorageBalance {
+            total: U128(balance),
+            available: U128(self.storage_available(&storage_account_id, balance)),
+        }
     }
 
+    /// withdraw amount of the available balance, all of it if None
     #[payable]
-    pub fn storage_withdraw(&mut self) {
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
         assert_one_yocto();
         let owner_id = env::predecessor_account_id();
-        let mut amount = self.storage_deposits.remove(&owner_id).unwrap_or(0);
-        let sales = self.by_owner_id.get(&owner_id);
-        let len = sales.map(|s| s.len()).unwrap_or_default();
-        let diff = u128::from(len) * STORAGE_PER_SALE;
-        amount -= diff;
+        let balance = self
+            .storage_deposits
+            .get(&owner_id)
+            .unwrap_or_else(|| env::panic(format!("{} is not registered", owner_id).as_bytes()));
+        let available = self.storage_available(&owner_id, balance);
+        let amount = amount.map_or(available, |amount| amount.0);
+        assert!(amount <= available, "Only {} can be withdrawn", available);
         if amount > 0 {
             Promise::new(owner_id.clone()).transfer(amount);
         }
-        if diff > 0 {
-            self.storage_deposits.insert(&owner_id, &diff);
+        let balance = balance - amount;
+        self.storage_deposits.insert(&owner_id, &balance);
+        StorageBalance {
+            total: U128(balance),
+            available: U128(available - amount),
         }
     }
 
+    /// refund the whole storage balance. With force the account's sales, offers, bundles and
+    /// proposed swaps are removed and their bids, offers and top-ups refunded, otherwise
+    /// there must be none. Returns false if the account wasn't registered
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = if let Some(balance) = self.storage_deposits.get(&account_id) {
+            balance
+        } else {
+            return false;
+        };
+        if self.storage_used(&account_id) > 0 {
+            assert!(
+                force.unwrap_or(false),
+                "Can't unregister with sales, offers, bundles or swaps, unless forced"
+            );
+            self.internal_remove_account_listings(&account_id);
+        }
+        self.storage_deposits.remove(&account_id);
+        if balance > 0 {
+            Promise::new(account_id).transfer(balance);
+        }
+        true
+    }
+
     // views
     pub fn supported_ft_token_ids(&self) -> Vec<AccountId> {
         self.ft_token_ids.to_vec()
     }
 
+    pub fn get_min_bid_increment(&self) -> BidIncrement {
+        self.min_bid_increment
+    }
+
     pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
         StorageBalanceBounds {
             min: U128(STORAGE_PER_SALE),
@@ -162,24 +388,24 @@ impl Contract {
         U128(STORAGE_PER_SALE)
     }
 
-    pub fn storage_balance_of(&self, account_id: ValidAccountId) -> U128 {
-        U128(self.storage_deposits.get(account_id.as_ref()).unwrap_or(0))
-    }
-
-    pub fn storage_paid(&self, account_id: ValidAccountId) -> U128 {
-        U128(self.storage_deposits.get(account_id.as_ref()).unwrap_or(0))
-    }
-
-    pub fn storage_amount(&self) -> U128 {
-        U128(STORAGE_PER_SALE)
+    pub fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+        let total = self.storage_deposits.get(account_id.as_ref())?;
+        Some(StorageBalance {
+            total: U128(total),
+            available: U128(self.storage_available(account_id.as_ref(), total)),
+        })
     }
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{testing_env, MockedBlockchain};
+    use crate::nft_callbacks::NonFungibleTokenApprovalsReceiver;
+    use crate::sale_views::{SaleQuery, SalesPage};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain, PromiseResult, RuntimeFeesConfig, VMConfig};
+    use std::convert::TryInto;
 
     fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
@@ -215,4 +441,1450 @@ mod tests {
             true
         );
     }
+
+    /// accounts(1) lists "token-1" of the nft contract accounts(2) with msg
+    fn list_sale(contract: &mut Contract, msg: &str) {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.nft_on_approve("token-1".to_string(), accounts(1), U64(0), msg.to_string());
+    }
+
+    #[test]
+    fn test_storage_management() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(3 * STORAGE_PER_SALE).build());
+        let balance = contract.storage_deposit(None, Some(true));
+        assert_eq!(balance.total.0, STORAGE_PER_SALE);
+        let balance = contract.storage_deposit(None, None);
+        assert_eq!(balance.total.0, 4 * STORAGE_PER_SALE);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.nft_on_approve(
+            "token-1".to_string(),
+            accounts(1),
+            U64(0),
+            r#"{"sale_conditions":{"near":"10"}}"#.to_string(),
+        );
+        // one sale fits in the minimum balance
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(balance.available.0, 3 * STORAGE_PER_SALE);
+
+        contract.nft_on_approve(
+            "token-2".to_string(),
+            accounts(1),
+            U64(0),
+            r#"{"sale_conditions":{"near":"10"}}"#.to_string(),
+        );
+        let storage_usage: u64 = ["token-1", "token-2"]
+            .iter()
+            .map(|token_id| {
+                let sale_key = format!("{}{}{}", accounts(2), DELIMETER, token_id);
+                contract.get_sale(sale_key, None).unwrap().storage_usage.0
+            })
+            .sum();
+        let used = Balance::from(storage_usage) * STORAGE_PRICE_PER_BYTE;
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(
+            balance.available.0,
+            4 * STORAGE_PER_SALE - max(used, STORAGE_PER_SALE)
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.remove_sale(accounts(2), "token-2".to_string());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        let balance = contract.storage_withdraw(Some(U128(STORAGE_PER_SALE)));
+        assert_eq!(balance.total.0, 3 * STORAGE_PER_SALE);
+        assert_eq!(balance.available.0, 2 * STORAGE_PER_SALE);
+
+        assert!(contract.storage_unregister(Some(true)));
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+        assert_eq!(contract.get_supply_sales().0, 0);
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister with sales")]
+    fn test_storage_unregister_with_sales() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(&mut contract, r#"{"sale_conditions":{"near":"10"}}"#);
+        testing_env!(get_context(accounts(1)).attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_settle_auction_without_bids() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"1"},"is_auction":true,"auction_end_at":"1000"}"#,
+        );
+        assert_eq!(contract.get_supply_sales().0, 1);
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.block_timestamp(1000 * 1_000_000).build());
+        contract.settle_auction(accounts(2), "token-1".to_string());
+        assert_eq!(contract.get_supply_sales().0, 0);
+        // the minimum balance stays registered
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(balance.total.0, STORAGE_PER_SALE);
+        assert_eq!(balance.available.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Auction has not ended")]
+    fn test_settle_auction_before_end() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"1"},"is_auction":true,"auction_end_at":"1000"}"#,
+        );
+        contract.settle_auction(accounts(2), "token-1".to_string());
+    }
+
+    #[test]
+    fn test_late_bid_extends_auction() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"1"},"is_auction":true,"auction_end_at":"1000","extension_window":"300","max_extension":"500"}"#,
+        );
+        assert!(get_logs()[0].contains(r#""auction_end_at":"1000""#));
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context
+            .block_timestamp(900 * 1_000_000)
+            .attached_deposit(10)
+            .build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        let sale = contract.get_sale(sale_key.clone(), None).unwrap();
+        assert_eq!(sale.auction_end_at, Some(U64(1200)));
+        let logs = get_logs();
+        assert!(logs
+            .last()
+            .unwrap()
+            .contains(r#""event":"auction_extended""#));
+        assert!(logs.last().unwrap().contains(r#""auction_end_at":"1200""#));
+
+        testing_env!(context
+            .block_timestamp(1150 * 1_000_000)
+            .attached_deposit(20)
+            .build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        let sale = contract.get_sale(sale_key.clone(), None).unwrap();
+        assert_eq!(sale.auction_end_at, Some(U64(1450)));
+
+        // capped at the original end plus max_extension
+        testing_env!(context
+            .block_timestamp(1400 * 1_000_000)
+            .attached_deposit(30)
+            .build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        let sale = contract.get_sale(sale_key, None).unwrap();
+        assert_eq!(sale.auction_end_at, Some(U64(1500)));
+        assert_eq!(sale.total_extension, U64(500));
+    }
+
+    #[test]
+    fn test_dutch_auction_price() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"1000"},"dutch_auction":{"floor_conditions":{"near":"400"},"start_at":"100","end_at":"400"}}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.block_timestamp(200 * 1_000_000).build());
+        let prices = contract.get_current_price(sale_key.clone());
+        assert_eq!(prices.get("near"), Some(&U128(800)));
+
+        testing_env!(context.block_timestamp(500 * 1_000_000).build());
+        let prices = contract.get_current_price(sale_key.clone());
+        assert_eq!(prices.get("near"), Some(&U128(400)));
+
+        // overpaying buys at the current price
+        testing_env!(context.attached_deposit(1000).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        assert!(contract.get_sale(sale_key, None).is_none());
+    }
+
+    #[test]
+    fn test_sealed_bid_auction() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true,"commit_end_at":"100","reveal_end_at":"200"}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+        let commit = |price: u128, salt: &str| {
+            Base64VecU8(env::sha256(format!("{}:{}", price, salt).as_bytes()))
+        };
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(100).build());
+        contract.commit_bid(accounts(2), "token-1".to_string(), commit(50, "a"), None);
+        let logs = get_logs();
+        assert!(logs[0].contains(r#""event":"bid_placed""#));
+        assert!(logs[0].contains(r#""price":"100""#));
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(60)
+            .build());
+        contract.commit_bid(accounts(2), "token-1".to_string(), commit(60, "b"), None);
+        // never revealed
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(100)
+            .build());
+        contract.commit_bid(accounts(2), "token-1".to_string(), commit(70, "c"), None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(150 * 1_000_000)
+            .attached_deposit(0)
+            .build());
+        assert_eq!(
+            contract.get_sealed_bid_phase(sale_key.clone()),
+            Some(SealedBidPhase::Reveal)
+        );
+        contract.reveal_bid(
+            accounts(2),
+            "token-1".to_string(),
+            U128(60),
+            "b".to_string(),
+        );
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.reveal_bid(
+            accounts(2),
+            "token-1".to_string(),
+            U128(50),
+            "a".to_string(),
+        );
+        let sale = contract.get_sale(sale_key.clone(), None).unwrap();
+        let commitments = sale.sealed_bid.unwrap().commitments;
+        assert_eq!(commitments[1].price, Some(U128(60)));
+
+        testing_env!(context.block_timestamp(200 * 1_000_000).build());
+        contract.settle_auction(accounts(2), "token-1".to_string());
+        assert!(contract.get_sale(sale_key, None).is_none());
+        assert_eq!(contract.get_protocol_fees("near".to_string()).accrued.0, 10);
+    }
+
+    #[test]
+    fn test_hidden_reserve_and_buy_now() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true,"auction_end_at":"1000","reserve_price":{"near":"50"},"hide_reserve":true,"buy_now_price":{"near":"100"}}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+        assert!(contract
+            .get_sale(sale_key.clone(), None)
+            .unwrap()
+            .reserve_price
+            .is_none());
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(20).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        assert!(!contract.is_reserve_met(sale_key.clone(), "near".to_string()));
+
+        // the buy-now price ends the auction, the 20 bid is refunded
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(120)
+            .build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        assert!(contract.get_sale(sale_key, None).is_none());
+    }
+
+    #[test]
+    fn test_expired_bid_does_not_block() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+
+        testing_env!(get_context(accounts(3)).attached_deposit(1000).build());
+        contract.offer(accounts(2), "token-1".to_string(), Some(U64(100)), None);
+        assert_eq!(
+            contract
+                .get_min_next_bid(sale_key.clone(), "near".to_string())
+                .0,
+            1001
+        );
+
+        testing_env!(get_context(accounts(4))
+            .block_timestamp(100 * 1_000_000)
+            .attached_deposit(20)
+            .build());
+        assert_eq!(
+            contract
+                .get_min_next_bid(sale_key.clone(), "near".to_string())
+                .0,
+            10
+        );
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        let bids = contract.get_bids(sale_key, "near".to_string(), U64(0), 10);
+        assert_eq!(bids.last().unwrap().owner_id, accounts(4).to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Bid can't expire before the auction ends")]
+    fn test_bid_expiring_before_auction_end() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true,"auction_end_at":"1000"}"#,
+        );
+        testing_env!(get_context(accounts(3)).attached_deposit(1000).build());
+        contract.offer(accounts(2), "token-1".to_string(), Some(U64(999)), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bid must be at least 110")]
+    fn test_min_bid_increment() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        contract.set_min_bid_increment(BidIncrement::BasisPoints(1000));
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(100).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        assert_eq!(
+            contract.get_min_next_bid(sale_key, "near".to_string()),
+            U128(110)
+        );
+        testing_env!(context.attached_deposit(109).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_withdraw_and_expire_bids() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(20).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        testing_env!(context.attached_deposit(1).build());
+        contract.withdraw_bid(
+            accounts(2),
+            "token-1".to_string(),
+            "near".try_into().unwrap(),
+        );
+        assert!(contract
+            .get_sale(sale_key.clone(), None)
+            .unwrap()
+            .bids
+            .is_empty());
+
+        testing_env!(context.attached_deposit(20).build());
+        contract.offer(accounts(2), "token-1".to_string(), Some(U64(100)), None);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(100 * 1_000_000)
+            .attached_deposit(0)
+            .build());
+        contract.remove_expired_bids(accounts(2), "token-1".to_string());
+        assert!(contract.get_sale(sale_key, None).unwrap().bids.is_empty());
+    }
+
+    #[test]
+    fn test_prune_expired_sales() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true,"starts_at":"100","expires_at":"1000"}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context
+            .block_timestamp(100 * 1_000_000)
+            .attached_deposit(20)
+            .build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        assert_eq!(contract.get_sale(sale_key, None).unwrap().bids.len(), 1);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(999 * 1_000_000)
+            .attached_deposit(0)
+            .build());
+        assert_eq!(contract.prune_expired_sales(U64(0), 10).0, 0);
+        testing_env!(context.block_timestamp(1000 * 1_000_000).build());
+        assert_eq!(contract.prune_expired_sales(U64(0), 10).0, 1);
+        assert_eq!(contract.get_supply_sales().0, 0);
+        assert_eq!(contract.get_supply_by_owner_id(accounts(1).into()).0, 0);
+        assert_eq!(contract.get_supply_by_bidder_id(accounts(3).into()).0, 0);
+        // the sale's bytes are released, the deposit stays with the owner
+        assert_eq!(contract.storage_used(&accounts(1).into()), 0);
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(balance.total.0, STORAGE_PER_SALE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sale has not started")]
+    fn test_sale_not_started() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"starts_at":"100"}"#,
+        );
+        testing_env!(get_context(accounts(3)).attached_deposit(10).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_verify_sale() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(&mut contract, r#"{"sale_conditions":{"near":"10"}}"#);
+        let verify_results = |owner_id: &str, is_approved: bool| {
+            testing_env!(
+                get_context(accounts(0)).build(),
+                VMConfig::default(),
+                RuntimeFeesConfig::default(),
+                HashMap::default(),
+                vec![
+                    PromiseResult::Successful(
+                        format!(r#"{{"token_id":"token-1","owner_id":"{}"}}"#, owner_id)
+                            .into_bytes()
+                    ),
+                    PromiseResult::Successful(is_approved.to_string().into_bytes()),
+                ]
+            );
+        };
+
+        verify_results(accounts(1).as_ref(), true);
+        assert!(contract.resolve_verify_sale(
+            accounts(2).into(),
+            "token-1".to_string(),
+            accounts(1).into(),
+            U64(0),
+        ));
+        assert_eq!(contract.get_supply_sales().0, 1);
+
+        verify_results(accounts(3).as_ref(), true);
+        assert!(!contract.resolve_verify_sale(
+            accounts(2).into(),
+            "token-1".to_string(),
+            accounts(1).into(),
+            U64(0),
+        ));
+        assert_eq!(contract.get_supply_sales().0, 0);
+        assert_eq!(contract.get_supply_by_owner_id(accounts(1).into()).0, 0);
+    }
+
+    #[test]
+    fn test_events() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true}"#,
+        );
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                r#"EVENT_JSON:{{"standard":"nft_market","version":"1.0.0","event":"sale_listed","data":[{{"owner_id":"{}","nft_contract_id":"{}","token_id":"token-1","approval_id":"0","sale_conditions":{{"near":"10"}},"is_auction":true,"is_sealed":false}}]}}"#,
+                accounts(1),
+                accounts(2)
+            )]
+        );
+
+        testing_env!(get_context(accounts(3)).attached_deposit(10).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        testing_env!(get_context(accounts(4)).attached_deposit(20).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        let logs = get_logs();
+        assert_eq!(logs.len(), 2);
+        assert!(logs[0].contains(&format!(
+            r#""event":"bid_refunded","data":[{{"bidder_id":"{}""#,
+            accounts(3)
+        )));
+        assert!(logs[1].contains(r#""event":"bid_placed""#));
+        assert!(logs[1].contains(r#""price":"20""#));
+    }
+
+    #[test]
+    fn test_reapprove_refunds_bids() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true}"#,
+        );
+        testing_env!(get_context(accounts(3)).attached_deposit(20).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        assert_eq!(contract.get_supply_by_bidder_id(accounts(3).into()).0, 1);
+
+        list_sale(&mut contract, r#"{"sale_conditions":{"near":"50"}}"#);
+        assert!(get_logs().iter().any(|log| log.contains(&format!(
+            r#""event":"bid_refunded","data":[{{"bidder_id":"{}""#,
+            accounts(3)
+        ))));
+        assert_eq!(contract.get_supply_by_bidder_id(accounts(3).into()).0, 0);
+        assert_eq!(contract.get_supply_sales().0, 1);
+        assert_eq!(contract.get_supply_by_owner_id(accounts(1).into()).0, 1);
+    }
+
+    #[test]
+    fn test_counter_offer() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(20).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.counter_offer(
+            accounts(2),
+            "token-1".to_string(),
+            "near".try_into().unwrap(),
+            U128(30),
+            None,
+        );
+        let sale = contract.get_sale(sale_key.clone(), None).unwrap();
+        assert_eq!(
+            sale.counter_offers["near"].bidder_id,
+            accounts(3).to_string()
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10)
+            .build());
+        contract.accept_counter_offer(accounts(2), "token-1".to_string());
+        assert!(contract.get_sale(sale_key, None).is_none());
+        assert_eq!(
+            contract.get_supply_by_bidder_id(accounts(3).to_string()).0,
+            0
+        );
+    }
+
+    /// resolve_purchase sees payout as the result of nft_transfer_payout
+    fn set_payout_result(payout: &Payout) {
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(payout).unwrap()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_protocol_fee() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        contract.set_protocol_fee_bps(250);
+
+        let sale = Sale::new(
+            accounts(1).into(),
+            U64(0),
+            accounts(2).into(),
+            "token-1".to_string(),
+            HashMap::new(),
+            None,
+        );
+        let mut payout = Payout::new();
+        payout.insert(accounts(1).into(), U128(9_000));
+        payout.insert(accounts(4).into(), U128(1_000));
+        set_payout_result(&payout);
+        let refund = contract.resolve_purchase(
+            "near".to_string(),
+            accounts(3).into(),
+            sale,
+            U128(10_000),
+            None,
+        );
+        assert_eq!(refund.0, 0);
+        assert_eq!(
+            contract.get_protocol_fees("near".to_string()).accrued.0,
+            250
+        );
+
+        testing_env!(get_context(accounts(0)).attached_deposit(1).build());
+        contract.withdraw_protocol_fees("near".try_into().unwrap(), Some(U128(20)));
+        let fees = contract.get_protocol_fees("near".to_string());
+        assert_eq!((fees.accrued.0, fees.withdrawn.0), (250, 20));
+
+        // a failed transfer puts the fees back
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+        assert!(!contract.resolve_withdraw_protocol_fees("near".to_string(), U128(20)));
+        let fees = contract.get_protocol_fees("near".to_string());
+        assert_eq!((fees.accrued.0, fees.withdrawn.0), (250, 0));
+    }
+
+    #[test]
+    fn test_trade_ledger() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let nft_contract_token = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+        assert!(contract.get_last_sale(nft_contract_token.clone()).is_none());
+        for seller_id in [1, 3] {
+            testing_env!(get_context(accounts(seller_id))
+                .attached_deposit(STORAGE_PER_SALE)
+                .build());
+            contract.storage_deposit(None, None);
+        }
+
+        for (seller_id, buyer_id, price) in [(1, 3, 100), (3, 4, 150)] {
+            let sale = Sale::new(
+                accounts(seller_id).into(),
+                U64(0),
+                accounts(2).into(),
+                "token-1".to_string(),
+                HashMap::new(),
+                None,
+            );
+            // as process_purchase does
+            contract.internal_hold_storage(&sale.owner_id, sale.trade_storage_max());
+            let mut payout = Payout::new();
+            payout.insert(accounts(seller_id).into(), U128(price));
+            set_payout_result(&payout);
+            contract.resolve_purchase(
+                "near".to_string(),
+                accounts(buyer_id).into(),
+                sale,
+                U128(price),
+                None,
+            );
+        }
+
+        assert_eq!(contract.get_supply_trades().0, 2);
+        // the seller's deposit paid for the trade's storage
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert!(balance.total.0 < STORAGE_PER_SALE);
+        let last_sale = contract.get_last_sale(nft_contract_token.clone()).unwrap();
+        assert_eq!(last_sale.buyer_id, accounts(4).to_string());
+        assert_eq!(last_sale.price, U128(150));
+        assert_eq!(contract.get_supply_trades_by_token(nft_contract_token).0, 2);
+        let trades = contract.get_trades_by_account_id(accounts(3).into(), U64(0), 10);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].seller_id, accounts(1).to_string());
+        assert_eq!(
+            contract
+                .get_trades_by_nft_contract_id(accounts(2).into(), U64(1), 10)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let day_ns = 24 * 60 * 60 * 1000 * 1_000_000;
+        // two trades on day 0, one by a returning buyer on day 3 and one on day 8
+        for (day, buyer_id, price) in [(0, 3, 100), (0, 4, 50), (3, 3, 20), (8, 4, 7)] {
+            let sale = Sale::new(
+                accounts(1).into(),
+                U64(0),
+                accounts(2).into(),
+                "nft-2048:1".to_string(),
+                HashMap::new(),
+                Some("nft-2048".to_string()),
+            );
+            let mut payout = Payout::new();
+            payout.insert(accounts(1).into(), U128(price));
+            testing_env!(
+                get_context(accounts(0))
+                    .block_timestamp(day * day_ns)
+                    .build(),
+                VMConfig::default(),
+                RuntimeFeesConfig::default(),
+                HashMap::default(),
+                vec![PromiseResult::Successful(
+                    near_sdk::serde_json::to_vec(&payout).unwrap()
+                )]
+            );
+            contract.resolve_purchase(
+                "near".to_string(),
+                accounts(buyer_id).into(),
+                sale,
+                U128(price),
+                None,
+            );
+        }
+
+        let stats = contract.get_stats_by_nft_contract_id(accounts(2).into());
+        assert_eq!(stats.volume["near"], U128(177));
+        assert_eq!(stats.trade_count, U64(4));
+        assert_eq!((stats.buyer_count, stats.seller_count), (U64(2), U64(1)));
+        assert_eq!(stats.volume_24h["near"], U128(7));
+        assert_eq!(stats.volume_7d["near"], U128(27));
+        let stats = contract.get_stats_by_nft_token_type("nft-2048".to_string());
+        assert_eq!(stats.trade_count, U64(4));
+    }
+
+    #[test]
+    fn test_floor_price() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(5 * STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        for (token_id, msg) in [
+            ("token-1", r#"{"sale_conditions":{"near":"30"}}"#),
+            // not listed until then
+            (
+                "token-5",
+                r#"{"sale_conditions":{"near":"2"},"starts_at":"1000"}"#,
+            ),
+            ("token-2", r#"{"sale_conditions":{"near":"10"}}"#),
+            ("token-3", r#"{"sale_conditions":{"near":"20"}}"#),
+            (
+                "token-4",
+                r#"{"sale_conditions":{"near":"5"},"is_auction":true}"#,
+            ),
+        ] {
+            contract.nft_on_approve(token_id.to_string(), accounts(1), U64(0), msg.to_string());
+        }
+        let floor_price = |contract: &Contract| {
+            contract.get_floor_price_by_nft_contract_id(accounts(2).into(), "near".to_string())
+        };
+        assert_eq!(floor_price(&contract), Some(U128(10)));
+
+        let token_ids = |sales: Vec<Sale>| -> Vec<String> {
+            sales.into_iter().map(|sale| sale.token_id).collect()
+        };
+        let sales = contract.get_sales_by_nft_contract_id_and_price(
+            accounts(2).into(),
+            "near".to_string(),
+            None,
+            Some(U128(20)),
+            Some(true),
+            U64(0),
+            10,
+        );
+        assert_eq!(token_ids(sales), vec!["token-3", "token-2"]);
+        let sales = contract.get_sales_by_nft_contract_id_and_price(
+            accounts(2).into(),
+            "near".to_string(),
+            Some(U128(15)),
+            None,
+            None,
+            U64(0),
+            10,
+        );
+        assert_eq!(token_ids(sales), vec!["token-3", "token-1"]);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.update_price(
+            accounts(2),
+            "token-1".to_string(),
+            "near".try_into().unwrap(),
+            U128(1),
+        );
+        assert_eq!(floor_price(&contract), Some(U128(1)));
+        contract.remove_sale(accounts(2), "token-1".to_string());
+        assert_eq!(floor_price(&contract), Some(U128(10)));
+        testing_env!(context.block_timestamp(1000 * 1_000_000).build());
+        assert_eq!(floor_price(&contract), Some(U128(2)));
+    }
+
+    #[test]
+    fn test_get_sales_query() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(4 * STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        for (token_id, msg) in [
+            ("token-1", r#"{"sale_conditions":{"near":"30"}}"#),
+            ("token-2", r#"{"sale_conditions":{"near":"10"}}"#),
+            (
+                "token-3",
+                r#"{"sale_conditions":{"near":"20"},"is_auction":true}"#,
+            ),
+            ("token-4", r#"{"sale_conditions":{"near":"40"}}"#),
+        ] {
+            contract.nft_on_approve(token_id.to_string(), accounts(1), U64(0), msg.to_string());
+        }
+        let token_ids = |page: &SalesPage| -> Vec<String> {
+            page.sales
+                .iter()
+                .map(|sale| sale.token_id.clone())
+                .collect()
+        };
+
+        let query = SaleQuery {
+            nft_contract_id: Some(accounts(2).into()),
+            ft_token_id: Some("near".to_string()),
+            min_price: Some(U128(15)),
+            is_auction: Some(false),
+            ..Default::default()
+        };
+        let page = contract.get_sales(query, 1);
+        assert_eq!(token_ids(&page), vec!["token-1"]);
+        let cursor = page.next_cursor.unwrap();
+
+        // a removal doesn't shift the next page
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.remove_sale(accounts(2), "token-2".to_string());
+        let query = SaleQuery {
+            owner_id: Some(accounts(1).into()),
+            ft_token_id: Some("near".to_string()),
+            min_price: Some(U128(15)),
+            is_auction: Some(false),
+            cursor: Some(cursor),
+            ..Default::default()
+        };
+        let page = contract.get_sales(query, 10);
+        assert_eq!(token_ids(&page), vec!["token-4"]);
+        assert!(page.next_cursor.is_none());
+
+        let query = SaleQuery {
+            owner_id: Some(accounts(1).into()),
+            ..Default::default()
+        };
+        assert!(contract.get_sales(query, 0).sales.is_empty());
+        let query = SaleQuery {
+            owner_id: Some(accounts(3).into()),
+            ..Default::default()
+        };
+        assert!(contract.get_sales(query, 10).sales.is_empty());
+    }
+
+    #[test]
+    fn test_newest_sales() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(5 * STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        for (listed_at, nft_contract_id, token_id) in [
+            (100, 2, "token-1"),
+            (200, 3, "token-2"),
+            (300, 2, "token-3"),
+        ] {
+            testing_env!(context
+                .predecessor_account_id(accounts(nft_contract_id))
+                .block_timestamp(listed_at * 1_000_000)
+                .build());
+            contract.nft_on_approve(
+                token_id.to_string(),
+                accounts(1),
+                U64(0),
+                r#"{"sale_conditions":{"near":"10"}}"#.to_string(),
+            );
+        }
+        let token_ids = |sales: Vec<Sale>| -> Vec<String> {
+            sales.into_iter().map(|sale| sale.token_id).collect()
+        };
+
+        assert_eq!(
+            token_ids(contract.get_newest_sales(None, 2, None)),
+            vec!["token-3", "token-2"]
+        );
+        assert_eq!(
+            token_ids(contract.get_newest_sales(Some(accounts(2).into()), 10, None)),
+            vec!["token-3", "token-1"]
+        );
+        assert_eq!(
+            token_ids(
+                contract
+                    .get_sales_listed_since(None, U64(200), 10, None, None)
+                    .sales
+            ),
+            vec!["token-2", "token-3"]
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.remove_sale(accounts(2), "token-3".to_string());
+        assert_eq!(
+            token_ids(contract.get_newest_sales(Some(accounts(2).into()), 10, None)),
+            vec!["token-1"]
+        );
+
+        // sales listed at the same time page once each
+        for token_id in ["token-4", "token-5"] {
+            testing_env!(context
+                .predecessor_account_id(accounts(3))
+                .block_timestamp(400 * 1_000_000)
+                .attached_deposit(0)
+                .build());
+            contract.nft_on_approve(
+                token_id.to_string(),
+                accounts(1),
+                U64(0),
+                r#"{"sale_conditions":{"near":"10"}}"#.to_string(),
+            );
+        }
+        let page = contract.get_sales_listed_since(None, U64(400), 1, None, None);
+        assert_eq!(token_ids(page.sales), vec!["token-4"]);
+        let page = contract.get_sales_listed_since(None, U64(400), 10, None, page.next_cursor);
+        assert_eq!(token_ids(page.sales), vec!["token-5"]);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_trade_storage_max() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        // the longest account ids, every record the trade touches is new
+        let long_id = |name: &str| -> ValidAccountId {
+            format!("{}.{}", name, "x".repeat(63 - name.len()))
+                .try_into()
+                .unwrap()
+        };
+        let (seller_id, buyer_id, nft_contract_id) =
+            (long_id("seller"), long_id("buyer"), long_id("nft"));
+        let token_id = format!("edition-{}", "1".repeat(100));
+        let mut context = get_context(seller_id.clone());
+        testing_env!(context.attached_deposit(10 * STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .predecessor_account_id(nft_contract_id.clone())
+            .attached_deposit(0)
+            .build());
+        contract.nft_on_approve(
+            token_id.clone(),
+            seller_id.clone(),
+            U64(0),
+            r#"{"sale_conditions":{"near":"100"},"token_type":"edition"}"#.to_string(),
+        );
+        let sale_key = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let sale = contract.get_sale(sale_key, None).unwrap();
+
+        testing_env!(get_context(buyer_id.clone()).attached_deposit(100).build());
+        contract.offer(nft_contract_id, token_id, None, Some(long_id("referrer")));
+        let held = contract
+            .storage_balance_of(seller_id.clone())
+            .unwrap()
+            .total
+            .0;
+        let mut payout = Payout::new();
+        payout.insert(seller_id.to_string(), U128(100));
+        set_payout_result(&payout);
+        contract.resolve_purchase(
+            "near".to_string(),
+            buyer_id.into(),
+            sale,
+            U128(100),
+            Some(long_id("referrer").into()),
+        );
+        // part of what was held for the trade came back
+        let total = contract.storage_balance_of(seller_id).unwrap().total.0;
+        assert!(total > held);
+    }
+
+    #[test]
+    fn test_storage_per_sale_bound() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), Some(vec![accounts(5)]), None);
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(10 * STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        // an owner's first sale indexes everything new, listing plus trade reserve fits the minimum
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(0)
+            .build());
+        contract.nft_on_approve(
+            "token-1".to_string(),
+            accounts(1),
+            U64(0),
+            r#"{"sale_conditions":{"near":"10"}}"#.to_string(),
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+        let sale = contract.get_sale(sale_key, None).unwrap();
+        assert!(Balance::from(sale.storage_usage.0) * STORAGE_PRICE_PER_BYTE <= STORAGE_PER_SALE);
+
+        // flat charged records at their first and largest
+        let flat_max = |before: StorageUsage| {
+            assert!(
+                Balance::from(env::storage_usage() - before) * STORAGE_PRICE_PER_BYTE
+                    <= STORAGE_PER_SALE
+            );
+        };
+        let before = env::storage_usage();
+        contract.nft_on_approve(
+            "edition-1".to_string(),
+            accounts(1),
+            U64(0),
+            r#"{"bundle_id":"b","size":2,"sale_conditions":{"near":"10"},"token_type":"edition"}"#
+                .to_string(),
+        );
+        flat_max(before);
+        testing_env!(get_context(accounts(1)).build());
+        let before = env::storage_usage();
+        let tokens = |prefix: &str| {
+            vec![SwapTokenArgs {
+                nft_contract_id: accounts(2),
+                token_id: format!("{}-1", prefix),
+            }]
+        };
+        contract.propose_swap(
+            accounts(3),
+            tokens("give"),
+            tokens("take"),
+            Some(SwapTopUpArgs {
+                payer_id: accounts(3),
+                ft_token_id: accounts(5),
+                amount: U128(10),
+            }),
+            None,
+        );
+        flat_max(before);
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(100).build());
+        let before = env::storage_usage();
+        contract.make_offer(accounts(2), Some("edition".to_string()), None, None);
+        flat_max(before);
+    }
+
+    #[test]
+    fn test_referral_fee() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        contract.set_protocol_fee_bps(250);
+        contract.set_referral_fee_bps(100);
+
+        let sale = Sale::new(
+            accounts(1).into(),
+            U64(0),
+            accounts(2).into(),
+            "token-1".to_string(),
+            HashMap::new(),
+            None,
+        );
+        let mut payout = Payout::new();
+        payout.insert(accounts(1).into(), U128(10_000));
+        set_payout_result(&payout);
+        contract.resolve_purchase(
+            "near".to_string(),
+            accounts(3).into(),
+            sale,
+            U128(10_000),
+            Some(accounts(4).into()),
+        );
+        let stats = contract.get_referral_stats(accounts(4).into());
+        assert_eq!(stats.purchases.0, 1);
+        assert_eq!(stats.volume["near"].0, 10_000);
+        assert_eq!(stats.earned["near"].0, 100);
+        assert_eq!(
+            contract.get_protocol_fees("near".to_string()).accrued.0,
+            250
+        );
+    }
+
+    #[test]
+    fn test_bundle() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(STORAGE_PER_SALE * 2).build());
+        contract.storage_deposit(None, None);
+        let msg = r#"{"bundle_id":"set","size":2,"sale_conditions":{"near":"100"}}"#;
+        for (nft_contract_id, token_id) in [(accounts(2), "token-1"), (accounts(3), "token-2")] {
+            testing_env!(context.predecessor_account_id(nft_contract_id).build());
+            contract.nft_on_approve(token_id.to_string(), accounts(1), U64(0), msg.to_string());
+        }
+        let bundle_key = format!("{}{}set", accounts(1), DELIMETER);
+        assert_eq!(
+            contract.get_bundle(bundle_key.clone()).unwrap().items.len(),
+            2
+        );
+
+        testing_env!(get_context(accounts(4)).attached_deposit(100).build());
+        contract.buy_bundle(bundle_key, None);
+        assert_eq!(contract.get_supply_bundles().0, 0);
+
+        // the second item didn't transfer, the first goes back to the seller
+        let bundle = Bundle {
+            owner_id: accounts(1).into(),
+            bundle_id: "set".to_string(),
+            size: 2,
+            items: vec![],
+            sale_conditions: HashMap::new(),
+            created_at: U64(0),
+        };
+        let items = vec![
+            BundleItem {
+                nft_contract_id: accounts(2).into(),
+                token_id: "token-1".to_string(),
+                approval_id: U64(0),
+                token_type: None,
+            },
+            BundleItem {
+                nft_contract_id: accounts(3).into(),
+                token_id: "token-2".to_string(),
+                approval_id: U64(0),
+                token_type: None,
+            },
+        ];
+        let mut payout = Payout::new();
+        payout.insert(accounts(1).into(), U128(50));
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap()),
+                PromiseResult::Failed,
+            ]
+        );
+        let refund = contract.resolve_bundle_purchase(
+            "near".to_string(),
+            accounts(4).into(),
+            Bundle { items, ..bundle },
+            U128(100),
+            None,
+        );
+        assert_eq!(refund.0, 100);
+        let logs = get_logs();
+        let failed = logs.last().unwrap();
+        assert!(failed.contains(r#""event":"purchase_failed""#));
+        assert!(failed.contains(r#""bundle_id":"set""#));
+        assert!(failed.contains(r#""token_id":"token-2""#));
+
+        // each item reports the payout of its own royalties
+        let mut other_payout = Payout::new();
+        other_payout.insert(accounts(2).into(), U128(50));
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap()),
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&other_payout).unwrap()),
+            ]
+        );
+        let items = vec![
+            BundleItem {
+                nft_contract_id: accounts(2).into(),
+                token_id: "token-1".to_string(),
+                approval_id: U64(0),
+                token_type: None,
+            },
+            BundleItem {
+                nft_contract_id: accounts(3).into(),
+                token_id: "token-2".to_string(),
+                approval_id: U64(0),
+                token_type: None,
+            },
+        ];
+        let bundle = Bundle {
+            owner_id: accounts(1).into(),
+            bundle_id: "set".to_string(),
+            size: 2,
+            items,
+            sale_conditions: HashMap::new(),
+            created_at: U64(0),
+        };
+        let refund = contract.resolve_bundle_purchase(
+            "near".to_string(),
+            accounts(4).into(),
+            bundle,
+            U128(100),
+            None,
+        );
+        assert_eq!(refund.0, 0);
+        let logs = get_logs();
+        let succeeded = logs.last().unwrap();
+        assert!(succeeded.contains(r#""event":"purchase_succeeded""#));
+        assert!(succeeded.contains(r#""payout":{"bob":"50"}"#));
+        assert!(succeeded.contains(r#""payout":{"charlie":"50"}"#));
+    }
+
+    #[test]
+    fn test_swap() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(0).build());
+        let swap_id = contract.propose_swap(
+            accounts(4),
+            vec![SwapTokenArgs {
+                nft_contract_id: accounts(2),
+                token_id: "token-1".to_string(),
+            }],
+            vec![SwapTokenArgs {
+                nft_contract_id: accounts(3),
+                token_id: "token-2".to_string(),
+            }],
+            Some(SwapTopUpArgs {
+                payer_id: accounts(4),
+                ft_token_id: "near".try_into().unwrap(),
+                amount: U128(10),
+            }),
+            None,
+        );
+        let msg = format!(r#"{{"swap_id":"{}"}}"#, swap_id.0);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.nft_on_approve("token-1".to_string(), accounts(1), U64(0), msg.clone());
+
+        let mut context = get_context(accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.nft_on_approve("token-2".to_string(), accounts(4), U64(0), msg);
+        assert!(!contract.get_swap(swap_id).unwrap().top_up.unwrap().is_paid);
+
+        testing_env!(get_context(accounts(4)).attached_deposit(10).build());
+        contract.pay_swap_top_up(swap_id);
+        assert!(contract.get_swap(swap_id).is_none());
+        assert_eq!(
+            contract
+                .get_supply_swaps_by_account_id(accounts(1).to_string())
+                .0,
+            0
+        );
+    }
+
+    #[test]
+    fn test_private_sale() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            &format!(
+                r#"{{"sale_conditions":{{"near":"10"}},"is_auction":true,"buyer_ids":["{}"]}}"#,
+                accounts(3)
+            ),
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+        assert!(contract.get_sale(sale_key.clone(), None).is_none());
+        assert!(contract
+            .get_sale(sale_key.clone(), Some(accounts(3).into()))
+            .is_some());
+        let sales = contract.get_sales_by_nft_contract_id(
+            accounts(2).into(),
+            U64(0),
+            10,
+            Some(accounts(1).into()),
+        );
+        assert_eq!(sales.len(), 1);
+
+        testing_env!(get_context(accounts(3)).attached_deposit(10).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        let sale = contract
+            .get_sale(sale_key, Some(accounts(3).into()))
+            .unwrap();
+        let bid = sale.bids["near"].last().unwrap();
+        assert_eq!(
+            (bid.owner_id.clone(), bid.price),
+            (accounts(3).into(), U128(10))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Private sale")]
+    fn test_private_sale_outsider() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        list_sale(
+            &mut contract,
+            &format!(
+                r#"{{"sale_conditions":{{"near":"10"}},"is_auction":true,"buyer_ids":["{}"]}}"#,
+                accounts(3)
+            ),
+        );
+        testing_env!(get_context(accounts(4)).attached_deposit(20).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_bids_by_bidder() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, Some(2));
+        list_sale(
+            &mut contract,
+            r#"{"sale_conditions":{"near":"10"},"is_auction":true}"#,
+        );
+        let sale_key = format!("{}{}{}", accounts(2), DELIMETER, "token-1");
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(20).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        assert_eq!(contract.get_supply_by_bidder_id(accounts(3).into()).0, 1);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(30)
+            .build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+        assert_eq!(contract.get_supply_by_bidder_id(accounts(3).into()).0, 0);
+        let bids = contract.get_bids_by_bidder(accounts(4).into(), U64(0), 10);
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].nft_contract_token, sale_key);
+        assert_eq!(bids[0].bid.price, U128(30));
+        let history = contract.get_bids(sale_key, "near".to_string(), U64(0), 10);
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn test_collection_offer() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(100).build());
+        let offer_id = contract.make_offer(accounts(2), Some("nft-2048".to_string()), None, None);
+        assert_eq!(
+            contract
+                .get_supply_offers_by_nft_token_type("nft-2048".to_string())
+                .0,
+            1
+        );
+
+        // the seller's deposit pays for the trade
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(0)
+            .build());
+        contract.nft_on_approve(
+            "nft-2048:1".to_string(),
+            accounts(1),
+            U64(0),
+            format!(r#"{{"offer_id":"{}","token_type":"nft-2048"}}"#, offer_id.0),
+        );
+        assert!(contract.get_offer(offer_id).is_none());
+        assert_eq!(contract.get_supply_offers().0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "TokenType should be substr of TokenId")]
+    fn test_collection_offer_claimed_token_type() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(STORAGE_PER_SALE).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(100).build());
+        let offer_id = contract.make_offer(accounts(2), Some("nft-2048".to_string()), None, None);
+
+        testing_env!(get_context(accounts(1))
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.nft_on_approve(
+            "other:1".to_string(),
+            accounts(1),
+            U64(0),
+            format!(r#"{{"offer_id":"{}","token_type":"nft-2048"}}"#, offer_id.0),
+        );
+    }
+
+    #[test]
+    fn test_offer_on_unlisted_token() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        let mut context = get_context(accounts(3));
+        testing_env!(context.attached_deposit(STORAGE_PER_SALE * 2).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .attached_deposit(100)
+            .block_timestamp(1_000 * 1_000_000)
+            .build());
+        contract.make_offer(
+            accounts(2),
+            None,
+            Some("token-1".to_string()),
+            Some(U64(2_000)),
+        );
+        let nft_contract_token = format!("{}{}token-1", accounts(2), DELIMETER);
+        let offers = contract.get_offers_by_token(nft_contract_token.clone(), U64(0), 10);
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].price.0, 100);
+
+        testing_env!(context
+            .attached_deposit(50)
+            .block_timestamp(1_000 * 1_000_000)
+            .build());
+        let offer_id = contract.make_offer(
+            accounts(2),
+            None,
+            Some("token-1".to_string()),
+            Some(U64(2_000)),
+        );
+        testing_env!(context
+            .attached_deposit(0)
+            .block_timestamp(2_000 * 1_000_000)
+            .build());
+        contract.remove_expired_offers(vec![offers[0].offer_id, offer_id]);
+        assert_eq!(contract.get_supply_offers_by_token(nft_contract_token).0, 0);
+        assert_eq!(contract.get_supply_offers().0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "No sale, use make_offer for unlisted tokens")]
+    fn test_bid_on_unlisted_token() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), None, None);
+        testing_env!(get_context(accounts(3)).attached_deposit(100).build());
+        contract.offer(accounts(2), "token-1".to_string(), None, None);
+    }
 }
@@ -9,9 +9,56 @@ pub struct SaleArgs {
     pub token_type: TokenType,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub is_auction: Option<bool>,
+    /// auction times are in milliseconds, like `Sale.created_at`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction_start_at: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction_end_at: Option<U64>,
+    /// bids placed less than extension_window before the end push the end back to
+    /// extension_window after the bid, by no more than max_extension in total
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extension_window: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_extension: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dutch_auction: Option<DutchAuction>,
+    /// sealed-bid auctions commit until commit_end_at and reveal until reveal_end_at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_end_at: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reveal_end_at: Option<U64>,
+    /// auctions only: sale_conditions become the opening price, with an optional
+    /// reserve for settling and a buy-now price that ends the auction at once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserve_price: Option<SaleConditions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hide_reserve: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buy_now_price: Option<SaleConditions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_bid_increment: Option<BidIncrement>,
+    /// a private sale only these accounts can buy or bid on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_ids: Option<Vec<ValidAccountId>>,
+    /// listing window, an expired sale can be pruned by anyone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starts_at: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<U64>,
+}
+
+/// approve with {"offer_id": "..."} to sell the token to an offer, see offer.rs.
+/// Needs gas for nft_transfer_payout and resolve_purchase, and a storage deposit that covers
+/// the trade
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptOfferArgs {
+    pub offer_id: U64,
+    /// added by the NFT contract
+    pub token_type: TokenType,
 }
 
-trait NonFungibleTokenApprovalsReceiver {
+pub trait NonFungibleTokenApprovalsReceiver {
     fn nft_on_approve(
         &mut self,
         token_id: TokenId,
@@ -46,24 +93,69 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
             "owner_id should be signer_id"
         );
 
-        // enforce signer's storage is enough to cover + 1 more sale
-
-        let storage_amount = self.storage_amount().0;
-        let owner_paid_storage = self.storage_deposits.get(&signer_id).unwrap_or(0);
-        let signer_storage_required =
-            (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
-        assert!(
-            owner_paid_storage >= signer_storage_required,
-            "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
-            owner_paid_storage,
-            signer_storage_required / STORAGE_PER_SALE,
-            STORAGE_PER_SALE
-        );
+        if let Ok(AcceptOfferArgs {
+            offer_id,
+            token_type,
+        }) = near_sdk::serde_json::from_str(&msg)
+        {
+            self.internal_accept_offer(
+                nft_contract_id,
+                token_id,
+                signer_id,
+                approval_id,
+                offer_id.0,
+                token_type,
+            );
+            return;
+        }
+
+        if let Ok(SwapArgs { swap_id }) = near_sdk::serde_json::from_str(&msg) {
+            self.internal_approve_swap_token(
+                nft_contract_id,
+                token_id,
+                signer_id,
+                approval_id,
+                swap_id.0,
+            );
+            return;
+        }
+
+        if let Ok(bundle_args) = near_sdk::serde_json::from_str::<BundleArgs>(&msg) {
+            self.internal_add_bundle_item(
+                signer_id,
+                BundleItem {
+                    nft_contract_id,
+                    token_id,
+                    approval_id,
+                    token_type: bundle_args.token_type.clone(),
+                },
+                bundle_args,
+            );
+            return;
+        }
+
+        // approving a listed token again replaces its sale, the old one's bids are refunded
+        self.internal_remove_stale_sale(nft_contract_id.clone(), token_id.clone());
+        let initial_storage_usage = env::storage_usage();
 
         let SaleArgs {
             sale_conditions,
             token_type,
             is_auction,
+            auction_start_at,
+            auction_end_at,
+            extension_window,
+            max_extension,
+            dutch_auction,
+            commit_end_at,
+            reveal_end_at,
+            reserve_price,
+            hide_reserve,
+            buy_now_price,
+            min_bid_increment,
+            buyer_ids,
+            starts_at,
+            expires_at,
         } = near_sdk::serde_json::from_str(&msg).expect("Not valid SaleArgs");
 
         for (ft_token_id, _price) in sale_conditions.clone() {
@@ -74,80 +166,185 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
             }
         }
 
-        // env::log(format!("add_sale for owner: {}", &owner_id).as_bytes());
+        let is_auction = is_auction.unwrap_or(false);
+        if auction_start_at.is_some() || auction_end_at.is_some() {
+            assert!(is_auction, "Auction times require is_auction");
+        }
+        if let Some(auction_end_at) = auction_end_at {
+            let now = current_time_ms();
+            let auction_start_at = auction_start_at.map(|t| t.0).unwrap_or(now);
+            assert!(
+                auction_end_at.0 > auction_start_at && auction_end_at.0 > now,
+                "Auction must end in the future and after it starts"
+            );
+            // bids in different tokens can't be compared when settling
+            assert_eq!(
+                sale_conditions.len(),
+                1,
+                "Timed auctions must be listed in exactly one token"
+            );
+        }
 
-        let bids = HashMap::new();
+        if extension_window.is_some() || max_extension.is_some() {
+            assert!(
+                auction_end_at.is_some(),
+                "Auction extension requires auction_end_at"
+            );
+        }
 
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        self.sales.insert(
-            &contract_and_token_id,
-            &Sale {
-                owner_id: owner_id.clone().into(),
-                approval_id,
-                nft_contract_id: nft_contract_id.clone(),
-                token_id: token_id.clone(),
-                sale_conditions,
-                bids,
-                created_at: U64(env::block_timestamp() / 1000000),
-                token_type: token_type.clone(),
-                is_auction: is_auction.unwrap_or(false),
-            },
-        );
+        if let Some(dutch_auction) = &dutch_auction {
+            assert!(!is_auction, "A Dutch auction can't also be an auction");
+            assert!(
+                dutch_auction.end_at.0 > dutch_auction.start_at.0,
+                "Dutch auction must end after it starts"
+            );
+            assert_eq!(
+                dutch_auction.floor_conditions.len(),
+                sale_conditions.len(),
+                "Dutch auction needs a floor price for each token"
+            );
+            for (ft_token_id, price) in &sale_conditions {
+                let floor = dutch_auction
+                    .floor_conditions
+                    .get(ft_token_id)
+                    .expect("Dutch auction needs a floor price for each token");
+                assert!(
+                    floor.0 <= price.0,
+                    "Floor price can't be more than the start price"
+                );
+            }
+        }
 
-        // extra for views
+        let sealed_bid = match (commit_end_at, reveal_end_at) {
+            (None, None) => None,
+            (Some(commit_end_at), Some(reveal_end_at)) => {
+                assert!(is_auction, "Sealed-bid auctions require is_auction");
+                assert!(
+                    auction_end_at.is_none() && dutch_auction.is_none(),
+                    "A sealed-bid auction can't be another kind of auction"
+                );
+                assert!(
+                    commit_end_at.0 > current_time_ms() && reveal_end_at.0 > commit_end_at.0,
+                    "Commit phase must end in the future and before the reveal phase"
+                );
+                assert_eq!(
+                    sale_conditions.len(),
+                    1,
+                    "Sealed-bid auctions must be listed in exactly one token"
+                );
+                Some(SealedBidAuction {
+                    commit_end_at,
+                    reveal_end_at,
+                    commitments: vec![],
+                })
+            }
+            _ => env::panic(b"Sealed-bid auctions need commit_end_at and reveal_end_at"),
+        };
 
-        let mut by_owner_id = self.by_owner_id.get(owner_id.as_ref()).unwrap_or_else(|| {
-            UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(owner_id.as_ref()),
+        let is_reserve_hidden = hide_reserve.unwrap_or(false);
+        if reserve_price.is_some() || buy_now_price.is_some() {
+            assert!(
+                is_auction && sealed_bid.is_none(),
+                "Reserve and buy-now prices are only for open auctions"
+            );
+        }
+        if is_reserve_hidden {
+            assert!(reserve_price.is_some(), "No reserve price to hide");
+        }
+        for (ft_token_id, price) in reserve_price.iter().chain(buy_now_price.iter()).flatten() {
+            let opening_price = sale_conditions.get(ft_token_id).unwrap_or_else(|| {
+                env::panic(format!("Not for sale in {}", ft_token_id).as_bytes())
+            });
+            assert!(
+                price.0 >= opening_price.0,
+                "Reserve and buy-now prices can't be less than the opening price"
+            );
+        }
+        if let (Some(reserve_price), Some(buy_now_price)) = (&reserve_price, &buy_now_price) {
+            for (ft_token_id, price) in buy_now_price {
+                if let Some(reserve) = reserve_price.get(ft_token_id) {
+                    assert!(
+                        price.0 >= reserve.0,
+                        "Buy-now price can't be less than the reserve price"
+                    );
                 }
-                .try_to_vec()
-                .unwrap(),
-            )
-        });
+            }
+        }
 
-        let owner_occupied_storage = u128::from(by_owner_id.len()) * STORAGE_PER_SALE;
-        assert!(
-            owner_paid_storage > owner_occupied_storage,
-            "User has more sales than storage paid"
-        );
-        by_owner_id.insert(&contract_and_token_id);
-        self.by_owner_id.insert(owner_id.as_ref(), &by_owner_id);
-
-        let mut by_nft_contract_id = self
-            .by_nft_contract_id
-            .get(&nft_contract_id)
-            .unwrap_or_else(|| {
-                UnorderedSet::new(
-                    StorageKey::ByNFTContractIdInner {
-                        account_id_hash: hash_account_id(&nft_contract_id),
-                    }
-                    .try_to_vec()
-                    .unwrap(),
-                )
+        // env::log(format!("add_sale for owner: {}", &owner_id).as_bytes());
+
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if let Some(expires_at) = expires_at {
+            let now = current_time_ms();
+            let starts_at = starts_at.map_or(now, |starts_at| starts_at.0);
+            assert!(
+                expires_at.0 > starts_at && expires_at.0 > now,
+                "Listing must expire in the future and after it starts"
+            );
+            let auction_ends_at = auction_end_at.or_else(|| {
+                sealed_bid
+                    .as_ref()
+                    .map(|sealed_bid| sealed_bid.reveal_end_at)
             });
-        by_nft_contract_id.insert(&token_id);
-        self.by_nft_contract_id
-            .insert(&nft_contract_id, &by_nft_contract_id);
+            if let Some(auction_ends_at) = auction_ends_at {
+                assert!(
+                    expires_at.0 > auction_ends_at.0,
+                    "Listing must expire after the auction ends"
+                );
+            }
+        }
+
+        let buyer_ids: Option<Vec<AccountId>> = buyer_ids.map(|buyer_ids| {
+            assert!(!buyer_ids.is_empty(), "Private sales need buyer_ids");
+            buyer_ids
+                .into_iter()
+                .map(|buyer_id| buyer_id.into())
+                .collect()
+        });
 
-        if let Some(token_type) = token_type {
+        if let Some(token_type) = &token_type {
             assert!(
-                token_id.contains(&token_type),
+                token_id.contains(token_type),
                 "TokenType should be substr of TokenId"
             );
-            let mut by_nft_token_type =
-                self.by_nft_token_type.get(&token_type).unwrap_or_else(|| {
-                    UnorderedSet::new(
-                        StorageKey::ByNFTTokenTypeInner {
-                            token_type_hash: hash_account_id(&token_type),
-                        }
-                        .try_to_vec()
-                        .unwrap(),
-                    )
-                });
-            by_nft_token_type.insert(&contract_and_token_id);
-            self.by_nft_token_type
-                .insert(&token_type, &by_nft_token_type);
         }
+        let mut sale = Sale {
+            is_auction,
+            auction_start_at,
+            auction_end_at,
+            extension_window,
+            max_extension,
+            dutch_auction,
+            sealed_bid,
+            reserve_price,
+            is_reserve_hidden,
+            buy_now_price,
+            min_bid_increment,
+            buyer_ids,
+            starts_at,
+            expires_at,
+            ..Sale::new(
+                owner_id.into(),
+                approval_id,
+                nft_contract_id,
+                token_id,
+                sale_conditions,
+                token_type,
+            )
+        };
+        self.sales.insert(&contract_and_token_id, &sale);
+        self.internal_add_listing(&sale);
+        self.internal_add_sale_prices(&sale);
+        MarketEvent::SaleListed(vec![SaleListedData::new(&sale)]).emit();
+
+        // the sale holds the bytes it took of the owner's storage deposit and those its trade
+        // can take
+        sale.storage_usage =
+            U64(env::storage_usage() - initial_storage_usage + sale.trade_storage_max());
+        self.sales.insert(&contract_and_token_id, &sale);
+        let sale_storage = self.sale_storage_by_owner_id.get(&signer_id).unwrap_or(0);
+        self.sale_storage_by_owner_id
+            .insert(&signer_id, &(sale_storage + sale.storage_usage.0));
+        self.assert_storage_paid(&signer_id);
     }
 }
@@ -0,0 +1,319 @@
+use crate::*;
+
+/// escrowed offer on any token of a contract, of one token type or on one token,
+/// listed or not
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Offer {
+    pub offer_id: U64,
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_type: TokenType,
+    pub token_id: Option<TokenId>,
+    pub ft_token_id: FungibleTokenId,
+    pub price: U128,
+    pub created_at: U64,
+    pub expires_at: Option<U64>,
+}
+
+impl Offer {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at.0)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OfferArgs {
+    pub nft_contract_id: ValidAccountId,
+    pub token_type: TokenType,
+    pub token_id: Option<TokenId>,
+    pub expires_at: Option<U64>,
+}
+
+/// ft_on_transfer msg for an offer paid in FTs: {"offer": {"nft_contract_id": ...}}
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MakeOfferArgs {
+    pub offer: OfferArgs,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// for accepting an offer see: nft_callbacks.rs
+
+    /// offer the attached deposit for any token of nft_contract_id, or only for token_type
+    /// or token_id, offers in FTs are made through ft_on_transfer
+    #[payable]
+    pub fn make_offer(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_type: TokenType,
+        token_id: Option<TokenId>,
+        expires_at: Option<U64>,
+    ) -> U64 {
+        let deposit = env::attached_deposit();
+        assert!(deposit > 0, "Attached deposit must be greater than 0");
+        U64(self.internal_make_offer(
+            nft_contract_id.into(),
+            token_type,
+            token_id,
+            expires_at,
+            "near".to_string(),
+            deposit,
+            env::predecessor_account_id(),
+        ))
+    }
+
+    #[payable]
+    pub fn cancel_offer(&mut self, offer_id: U64) {
+        assert_one_yocto();
+        let offer = self.internal_remove_offer(offer_id.0);
+        assert_eq!(
+            env::predecessor_account_id(),
+            offer.owner_id,
+            "Must be offer owner"
+        );
+        transfer_to(&offer.ft_token_id, &offer.owner_id, offer.price);
+    }
+
+    /// anyone can refund expired offers
+    pub fn remove_expired_offers(&mut self, offer_ids: Vec<U64>) {
+        let now = current_time_ms();
+        for offer_id in offer_ids {
+            let offer = self.offers.get(&offer_id.0).expect("No offer");
+            assert!(
+                offer.is_expired(now),
+                "Offer {} has not expired",
+                offer_id.0
+            );
+            self.internal_remove_offer(offer_id.0);
+            transfer_to(&offer.ft_token_id, &offer.owner_id, offer.price);
+        }
+    }
+}
+
+impl Contract {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn internal_make_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_type: TokenType,
+        token_id: Option<TokenId>,
+        expires_at: Option<U64>,
+        ft_token_id: AccountId,
+        amount: Balance,
+        owner_id: AccountId,
+    ) -> OfferId {
+        let now = current_time_ms();
+        if let Some(expires_at) = expires_at {
+            assert!(expires_at.0 > now, "Offer expiry must be in the future");
+        }
+        if !self.ft_token_ids.contains(&ft_token_id) {
+            env::panic(format!("Token {} not supported by this market", ft_token_id).as_bytes());
+        }
+        self.assert_storage_for_one_more(&owner_id);
+
+        let offer_id = self.next_offer_id;
+        self.next_offer_id += 1;
+        self.offers.insert(
+            &offer_id,
+            &Offer {
+                offer_id: U64(offer_id),
+                owner_id: owner_id.clone(),
+                nft_contract_id: nft_contract_id.clone(),
+                token_type: token_type.clone(),
+                token_id: token_id.clone(),
+                ft_token_id,
+                price: U128(amount),
+                created_at: U64(now),
+                expires_at,
+            },
+        );
+
+        let mut offers_by_owner_id = self.offers_by_owner_id.get(&owner_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::OffersByOwnerIdInner {
+                    account_id_hash: hash_account_id(&owner_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+        offers_by_owner_id.insert(&offer_id);
+        self.offers_by_owner_id
+            .insert(&owner_id, &offers_by_owner_id);
+
+        let mut offers_by_nft_contract_id = self
+            .offers_by_nft_contract_id
+            .get(&nft_contract_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::OffersByNFTContractIdInner {
+                        account_id_hash: hash_account_id(&nft_contract_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        offers_by_nft_contract_id.insert(&offer_id);
+        self.offers_by_nft_contract_id
+            .insert(&nft_contract_id, &offers_by_nft_contract_id);
+
+        if let Some(token_type) = token_type {
+            let mut offers_by_nft_token_type = self
+                .offers_by_nft_token_type
+                .get(&token_type)
+                .unwrap_or_else(|| {
+                    UnorderedSet::new(
+                        StorageKey::OffersByNFTTokenTypeInner {
+                            token_type_hash: hash_account_id(&token_type),
+                        }
+                        .try_to_vec()
+                        .unwrap(),
+                    )
+                });
+            offers_by_nft_token_type.insert(&offer_id);
+            self.offers_by_nft_token_type
+                .insert(&token_type, &offers_by_nft_token_type);
+        }
+
+        if let Some(token_id) = token_id {
+            let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+            let mut offers_by_token = self
+                .offers_by_token
+                .get(&contract_and_token_id)
+                .unwrap_or_else(|| {
+                    UnorderedSet::new(
+                        StorageKey::OffersByTokenInner {
+                            token_hash: hash_account_id(&contract_and_token_id),
+                        }
+                        .try_to_vec()
+                        .unwrap(),
+                    )
+                });
+            offers_by_token.insert(&offer_id);
+            self.offers_by_token
+                .insert(&contract_and_token_id, &offers_by_token);
+        }
+
+        offer_id
+    }
+
+    pub(crate) fn internal_remove_offer(&mut self, offer_id: OfferId) -> Offer {
+        let offer = self.offers.remove(&offer_id).expect("No offer");
+
+        let mut offers_by_owner_id = self
+            .offers_by_owner_id
+            .get(&offer.owner_id)
+            .expect("No offer by owner_id");
+        offers_by_owner_id.remove(&offer_id);
+        if offers_by_owner_id.is_empty() {
+            self.offers_by_owner_id.remove(&offer.owner_id);
+        } else {
+            self.offers_by_owner_id
+                .insert(&offer.owner_id, &offers_by_owner_id);
+        }
+
+        let mut offers_by_nft_contract_id = self
+            .offers_by_nft_contract_id
+            .get(&offer.nft_contract_id)
+            .expect("No offer by nft_contract_id");
+        offers_by_nft_contract_id.remove(&offer_id);
+        if offers_by_nft_contract_id.is_empty() {
+            self.offers_by_nft_contract_id
+                .remove(&offer.nft_contract_id);
+        } else {
+            self.offers_by_nft_contract_id
+                .insert(&offer.nft_contract_id, &offers_by_nft_contract_id);
+        }
+
+        if let Some(token_type) = &offer.token_type {
+            let mut offers_by_nft_token_type = self
+                .offers_by_nft_token_type
+                .get(token_type)
+                .expect("No offer by nft_token_type");
+            offers_by_nft_token_type.remove(&offer_id);
+            if offers_by_nft_token_type.is_empty() {
+                self.offers_by_nft_token_type.remove(token_type);
+            } else {
+                self.offers_by_nft_token_type
+                    .insert(token_type, &offers_by_nft_token_type);
+            }
+        }
+
+        if let Some(token_id) = &offer.token_id {
+            let contract_and_token_id =
+                format!("{}{}{}", offer.nft_contract_id, DELIMETER, token_id);
+            let mut offers_by_token = self
+                .offers_by_token
+                .get(&contract_and_token_id)
+                .expect("No offer by token");
+            offers_by_token.remove(&offer_id);
+            if offers_by_token.is_empty() {
+                self.offers_by_token.remove(&contract_and_token_id);
+            } else {
+                self.offers_by_token
+                    .insert(&contract_and_token_id, &offers_by_token);
+            }
+        }
+
+        offer
+    }
+
+    /// the token owner approved the market with an offer_id, sell the token at the offer price
+    pub(crate) fn internal_accept_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+        offer_id: OfferId,
+        token_type: TokenType,
+    ) -> Promise {
+        let offer = self.internal_remove_offer(offer_id);
+        assert_eq!(
+            offer.nft_contract_id, nft_contract_id,
+            "Offer is for another NFT contract"
+        );
+        if let Some(offer_token_type) = &offer.token_type {
+            // token_type comes from the approval msg, like for sales it must at least be part
+            // of token_id so an owner can't claim any type for an unrelated token
+            assert_eq!(
+                Some(offer_token_type),
+                token_type.as_ref(),
+                "Offer is for another token type"
+            );
+            assert!(
+                token_id.contains(offer_token_type),
+                "TokenType should be substr of TokenId"
+            );
+        }
+        if let Some(offer_token_id) = &offer.token_id {
+            assert_eq!(offer_token_id, &token_id, "Offer is for another token");
+        }
+        assert!(!offer.is_expired(current_time_ms()), "Offer has expired");
+        assert_ne!(offer.owner_id, owner_id, "Cannot accept your own offer");
+
+        self.internal_remove_stale_sale(nft_contract_id.clone(), token_id.clone());
+
+        let mut sale_conditions = HashMap::new();
+        sale_conditions.insert(offer.ft_token_id.clone(), offer.price);
+        let sale = Sale::new(
+            owner_id,
+            approval_id,
+            nft_contract_id,
+            token_id,
+            sale_conditions,
+            token_type,
+        );
+        self.internal_nft_transfer_payout(
+            sale,
+            offer.ft_token_id,
+            offer.price,
+            offer.owner_id,
+            None,
+        )
+    }
+}
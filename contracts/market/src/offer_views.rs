@@ -0,0 +1,125 @@
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// views
+    pub fn get_supply_offers(&self) -> U64 {
+        U64(self.offers.len())
+    }
+
+    pub fn get_offer(&self, offer_id: U64) -> Option<Offer> {
+        self.offers.get(&offer_id.0)
+    }
+
+    pub fn get_supply_offers_by_owner_id(&self, account_id: AccountId) -> U64 {
+        let offers_by_owner_id = self.offers_by_owner_id.get(&account_id);
+        if let Some(offers_by_owner_id) = offers_by_owner_id {
+            U64(offers_by_owner_id.len())
+        } else {
+            U64(0)
+        }
+    }
+
+    pub fn get_offers_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Offer> {
+        let offers_by_owner_id = self.offers_by_owner_id.get(&account_id);
+        if let Some(offers_by_owner_id) = offers_by_owner_id {
+            self.offers_page(&offers_by_owner_id, from_index, limit)
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn get_supply_offers_by_nft_contract_id(&self, nft_contract_id: AccountId) -> U64 {
+        let offers_by_nft_contract_id = self.offers_by_nft_contract_id.get(&nft_contract_id);
+        if let Some(offers_by_nft_contract_id) = offers_by_nft_contract_id {
+            U64(offers_by_nft_contract_id.len())
+        } else {
+            U64(0)
+        }
+    }
+
+    /// every offer on the contract, with or without a token type
+    pub fn get_offers_by_nft_contract_id(
+        &self,
+        nft_contract_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Offer> {
+        let offers_by_nft_contract_id = self.offers_by_nft_contract_id.get(&nft_contract_id);
+        if let Some(offers_by_nft_contract_id) = offers_by_nft_contract_id {
+            self.offers_page(&offers_by_nft_contract_id, from_index, limit)
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn get_supply_offers_by_token(&self, nft_contract_token: ContractAndTokenId) -> U64 {
+        let offers_by_token = self.offers_by_token.get(&nft_contract_token);
+        if let Some(offers_by_token) = offers_by_token {
+            U64(offers_by_token.len())
+        } else {
+            U64(0)
+        }
+    }
+
+    /// offers naming this token, listed or not
+    pub fn get_offers_by_token(
+        &self,
+        nft_contract_token: ContractAndTokenId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Offer> {
+        let offers_by_token = self.offers_by_token.get(&nft_contract_token);
+        if let Some(offers_by_token) = offers_by_token {
+            self.offers_page(&offers_by_token, from_index, limit)
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn get_supply_offers_by_nft_token_type(&self, token_type: String) -> U64 {
+        let offers_by_nft_token_type = self.offers_by_nft_token_type.get(&token_type);
+        if let Some(offers_by_nft_token_type) = offers_by_nft_token_type {
+            U64(offers_by_nft_token_type.len())
+        } else {
+            U64(0)
+        }
+    }
+
+    pub fn get_offers_by_nft_token_type(
+        &self,
+        token_type: String,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Offer> {
+        let offers_by_nft_token_type = self.offers_by_nft_token_type.get(&token_type);
+        if let Some(offers_by_nft_token_type) = offers_by_nft_token_type {
+            self.offers_page(&offers_by_nft_token_type, from_index, limit)
+        } else {
+            vec![]
+        }
+    }
+}
+
+impl Contract {
+    fn offers_page(
+        &self,
+        offer_ids: &UnorderedSet<OfferId>,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Offer> {
+        let mut tmp = vec![];
+        let keys = offer_ids.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + limit, offer_ids.len());
+        for i in start..end {
+            tmp.push(self.offers.get(&keys.get(i).unwrap()).unwrap());
+        }
+        tmp
+    }
+}
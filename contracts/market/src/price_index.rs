@@ -0,0 +1,111 @@
+use crate::*;
+use std::ops::Bound;
+
+/// fixed-price sales of one collection or token type in one FT, ordered by price then sale key
+pub type PriceIndex = TreeMap<(Balance, ContractAndTokenId), ()>;
+
+impl Sale {
+    /// only sales that can be bought right away at a price that doesn't change are indexed,
+    /// auctions, Dutch auctions and private sales are left out
+    pub fn is_price_indexed(&self) -> bool {
+        !self.is_auction && self.dutch_auction.is_none() && self.buyer_ids.is_none()
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_add_sale_prices(&mut self, sale: &Sale) {
+        self.internal_update_sale_prices(sale, true);
+    }
+
+    pub(crate) fn internal_remove_sale_prices(&mut self, sale: &Sale) {
+        self.internal_update_sale_prices(sale, false);
+    }
+
+    fn internal_update_sale_prices(&mut self, sale: &Sale, is_insert: bool) {
+        if !sale.is_price_indexed() {
+            return;
+        }
+        let contract_and_token_id = sale.contract_and_token_id();
+        for (ft_token_id, price) in &sale.sale_conditions {
+            let entry = (price.0, contract_and_token_id.clone());
+            let index_key = format!("{}{}{}", sale.nft_contract_id, DELIMETER, ft_token_id);
+            update_price_index(
+                &mut self.prices_by_nft_contract_id,
+                StorageKey::PricesByNFTContractIdInner {
+                    index_hash: hash_account_id(&index_key),
+                },
+                &index_key,
+                &entry,
+                is_insert,
+            );
+            if let Some(token_type) = &sale.token_type {
+                let index_key = format!("{}{}{}", token_type, DELIMETER, ft_token_id);
+                update_price_index(
+                    &mut self.prices_by_nft_token_type,
+                    StorageKey::PricesByNFTTokenTypeInner {
+                        index_hash: hash_account_id(&index_key),
+                    },
+                    &index_key,
+                    &entry,
+                    is_insert,
+                );
+            }
+        }
+    }
+}
+
+fn update_price_index(
+    indexes: &mut LookupMap<String, PriceIndex>,
+    storage_key: StorageKey,
+    index_key: &str,
+    entry: &(Balance, ContractAndTokenId),
+    is_insert: bool,
+) {
+    let index_key = index_key.to_string();
+    let mut index = indexes
+        .get(&index_key)
+        .unwrap_or_else(|| TreeMap::new(storage_key.try_to_vec().unwrap()));
+    if is_insert {
+        index.insert(entry, &());
+    } else {
+        index.remove(entry);
+    }
+    if index.len() == 0 {
+        indexes.remove(&index_key);
+    } else {
+        indexes.insert(&index_key, &index);
+    }
+}
+
+/// sale keys of index priced between min_price and max_price, inclusive
+pub(crate) fn price_range<'a>(
+    index: &'a PriceIndex,
+    min_price: Option<U128>,
+    max_price: Option<U128>,
+    descending: bool,
+) -> Box<dyn Iterator<Item = (Balance, ContractAndTokenId)> + 'a> {
+    let min_price = min_price.map_or(0, |price| price.0);
+    let max_price = max_price.map_or(Balance::MAX, |price| price.0);
+    if min_price > max_price {
+        return Box::new(std::iter::empty());
+    }
+    // "" sorts before every sale key, so (price, "") bounds all the sales at price
+    let upper = max_price.checked_add(1).map(|price| (price, String::new()));
+    if descending {
+        let keys: Box<dyn Iterator<Item = ((Balance, ContractAndTokenId), ())>> = match upper {
+            Some(upper) => Box::new(index.iter_rev_from(upper)),
+            None => Box::new(index.iter_rev()),
+        };
+        Box::new(
+            keys.map(|(key, _)| key)
+                .take_while(move |(price, _)| *price >= min_price),
+        )
+    } else {
+        let upper = upper.map_or(Bound::Unbounded, Bound::Excluded);
+        Box::new(
+            index
+                .range((Bound::Included((min_price, String::new())), upper))
+                .map(|(key, _)| key),
+        )
+    }
+}
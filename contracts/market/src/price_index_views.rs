@@ -0,0 +1,108 @@
+#![allow(clippy::too_many_arguments)]
+
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// views, see price_index.rs for which sales are indexed. Sales outside their listing
+    /// window are left out
+
+    /// lowest fixed price of a collection in ft_token_id
+    pub fn get_floor_price_by_nft_contract_id(
+        &self,
+        nft_contract_id: AccountId,
+        ft_token_id: AccountId,
+    ) -> Option<U128> {
+        let index_key = format!("{}{}{}", nft_contract_id, DELIMETER, ft_token_id);
+        self.floor_price(&self.prices_by_nft_contract_id.get(&index_key)?)
+    }
+
+    pub fn get_floor_price_by_nft_token_type(
+        &self,
+        token_type: String,
+        ft_token_id: AccountId,
+    ) -> Option<U128> {
+        let index_key = format!("{}{}{}", token_type, DELIMETER, ft_token_id);
+        self.floor_price(&self.prices_by_nft_token_type.get(&index_key)?)
+    }
+
+    /// fixed-price sales of a collection in ft_token_id, cheapest first unless descending
+    pub fn get_sales_by_nft_contract_id_and_price(
+        &self,
+        nft_contract_id: AccountId,
+        ft_token_id: AccountId,
+        min_price: Option<U128>,
+        max_price: Option<U128>,
+        descending: Option<bool>,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Sale> {
+        let index_key = format!("{}{}{}", nft_contract_id, DELIMETER, ft_token_id);
+        self.sales_by_price(
+            self.prices_by_nft_contract_id.get(&index_key),
+            min_price,
+            max_price,
+            descending,
+            from_index,
+            limit,
+        )
+    }
+
+    pub fn get_sales_by_nft_token_type_and_price(
+        &self,
+        token_type: String,
+        ft_token_id: AccountId,
+        min_price: Option<U128>,
+        max_price: Option<U128>,
+        descending: Option<bool>,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Sale> {
+        let index_key = format!("{}{}{}", token_type, DELIMETER, ft_token_id);
+        self.sales_by_price(
+            self.prices_by_nft_token_type.get(&index_key),
+            min_price,
+            max_price,
+            descending,
+            from_index,
+            limit,
+        )
+    }
+}
+
+impl Contract {
+    fn floor_price(&self, index: &PriceIndex) -> Option<U128> {
+        let now = current_time_ms();
+        price_range(index, None, None, false)
+            .find(|(_, contract_and_token_id)| {
+                self.sales
+                    .get(contract_and_token_id)
+                    .map_or(false, |sale| sale.is_listed(now))
+            })
+            .map(|(price, _)| U128(price))
+    }
+
+    fn sales_by_price(
+        &self,
+        index: Option<PriceIndex>,
+        min_price: Option<U128>,
+        max_price: Option<U128>,
+        descending: Option<bool>,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Sale> {
+        let index = if let Some(index) = index {
+            index
+        } else {
+            return vec![];
+        };
+        let now = current_time_ms();
+        price_range(&index, min_price, max_price, descending.unwrap_or(false))
+            .map(|(_, contract_and_token_id)| self.sales.get(&contract_and_token_id).unwrap())
+            .filter(|sale| sale.is_listed(now))
+            .skip(from_index.0 as usize)
+            .take(limit as usize)
+            .map(|sale| sale.public_view())
+            .collect()
+    }
+}
@@ -6,6 +6,52 @@ use near_sdk::promise_result_as_success;
 pub struct Bid {
     pub owner_id: AccountId,
     pub price: U128,
+    /// an expired bid can't be accepted and anyone can refund it with remove_expired_bids
+    pub expires_at: Option<U64>,
+    /// paid the market's referral share if the bid buys the token
+    pub referrer_id: Option<AccountId>,
+}
+
+impl Bid {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at.0)
+    }
+}
+
+/// the seller's price to the current bidder in one token, accepted by topping up the bid
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CounterOffer {
+    pub bidder_id: AccountId,
+    pub price: U128,
+    pub expires_at: Option<U64>,
+}
+
+impl CounterOffer {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at.0)
+    }
+}
+
+/// minimum raise over the current bid, absolute amounts are in the bid token's smallest unit
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum BidIncrement {
+    Absolute(U128),
+    BasisPoints(u16),
+}
+
+impl BidIncrement {
+    pub fn next_bid(&self, current_bid: Balance) -> Balance {
+        let increment = match self {
+            BidIncrement::Absolute(amount) => amount.0,
+            BidIncrement::BasisPoints(bps) => basis_points_of(current_bid, *bps),
+        };
+        current_bid + std::cmp::max(increment, 1)
+    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
@@ -20,6 +66,229 @@ pub struct Sale {
     pub created_at: U64,
     pub is_auction: bool,
     pub token_type: Option<String>,
+    pub auction_start_at: Option<U64>,
+    pub auction_end_at: Option<U64>,
+    pub extension_window: Option<U64>,
+    pub max_extension: Option<U64>,
+    /// how far auction_end_at has been pushed back by late bids
+    pub total_extension: U64,
+    pub dutch_auction: Option<DutchAuction>,
+    pub sealed_bid: Option<SealedBidAuction>,
+    /// auctions only sell by settlement once the top bid reaches the reserve
+    pub reserve_price: Option<SaleConditions>,
+    /// views leave out a hidden reserve_price
+    pub is_reserve_hidden: bool,
+    /// offers at or above the buy-now price end an auction at once
+    pub buy_now_price: Option<SaleConditions>,
+    /// overrides the market's min_bid_increment
+    pub min_bid_increment: Option<BidIncrement>,
+    /// by bid token, dropped when the bid it answers is outbid or removed
+    pub counter_offers: HashMap<FungibleTokenId, CounterOffer>,
+    /// only these accounts can buy or bid, None for a public sale
+    pub buyer_ids: Option<Vec<AccountId>>,
+    /// listing window, anyone can prune the sale once it expired
+    pub starts_at: Option<U64>,
+    pub expires_at: Option<U64>,
+    /// bytes the listing took and the most its trade can take, held from the owner's storage
+    /// deposit
+    pub storage_usage: U64,
+}
+
+/// price falls linearly from sale_conditions to floor_conditions between start_at and end_at
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DutchAuction {
+    pub floor_conditions: SaleConditions,
+    pub start_at: U64,
+    pub end_at: U64,
+}
+
+/// bids are committed as hashes until commit_end_at and revealed until reveal_end_at
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SealedBidAuction {
+    pub commit_end_at: U64,
+    pub reveal_end_at: U64,
+    pub commitments: Vec<SealedBid>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SealedBid {
+    pub owner_id: AccountId,
+    /// sha256 of "{price}:{salt}"
+    pub hash: Base64VecU8,
+    /// escrowed amount, at least the revealed price
+    pub deposit: U128,
+    /// set once revealed
+    pub price: Option<U128>,
+    pub referrer_id: Option<AccountId>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum SealedBidPhase {
+    Commit,
+    Reveal,
+    Ended,
+}
+
+impl Sale {
+    /// fixed price sale, see nft_on_approve for the other kinds
+    pub fn new(
+        owner_id: AccountId,
+        approval_id: U64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        sale_conditions: SaleConditions,
+        token_type: TokenType,
+    ) -> Self {
+        Self {
+            owner_id,
+            approval_id,
+            nft_contract_id,
+            token_id,
+            sale_conditions,
+            bids: HashMap::new(),
+            created_at: U64(current_time_ms()),
+            is_auction: false,
+            token_type,
+            auction_start_at: None,
+            auction_end_at: None,
+            extension_window: None,
+            max_extension: None,
+            total_extension: U64(0),
+            dutch_auction: None,
+            sealed_bid: None,
+            reserve_price: None,
+            is_reserve_hidden: false,
+            buy_now_price: None,
+            min_bid_increment: None,
+            counter_offers: HashMap::new(),
+            buyer_ids: None,
+            starts_at: None,
+            expires_at: None,
+            storage_usage: U64(0),
+        }
+    }
+
+    pub fn contract_and_token_id(&self) -> ContractAndTokenId {
+        format!("{}{}{}", self.nft_contract_id, DELIMETER, self.token_id)
+    }
+
+    /// reserved at listing, see trade_storage_max
+    pub fn trade_storage_max(&self) -> StorageUsage {
+        trade_storage_max(
+            &self.nft_contract_id,
+            &self.token_id,
+            &self.owner_id,
+            self.token_type.as_ref(),
+        )
+    }
+
+    pub fn is_buyer_allowed(&self, account_id: &str) -> bool {
+        self.buyer_ids.as_ref().map_or(true, |buyer_ids| {
+            buyer_ids.iter().any(|buyer_id| buyer_id == account_id)
+        })
+    }
+
+    /// private sales are only shown to their owner and buyers
+    pub fn is_visible_to(&self, viewer_id: Option<&AccountId>) -> bool {
+        self.buyer_ids.is_none()
+            || viewer_id.map_or(false, |viewer_id| {
+                viewer_id == &self.owner_id || self.is_buyer_allowed(viewer_id)
+            })
+    }
+
+    /// sale as returned by views, without a hidden reserve price
+    pub fn public_view(mut self) -> Self {
+        if self.is_reserve_hidden {
+            self.reserve_price = None;
+        }
+        self
+    }
+
+    pub fn reserve_price(&self, ft_token_id: &str) -> Balance {
+        self.reserve_price
+            .as_ref()
+            .and_then(|reserve_price| reserve_price.get(ft_token_id))
+            .map_or(0, |price| price.0)
+    }
+
+    pub fn buy_now_price(&self, ft_token_id: &str) -> Option<Balance> {
+        self.buy_now_price
+            .as_ref()
+            .and_then(|buy_now_price| buy_now_price.get(ft_token_id))
+            .map(|price| price.0)
+    }
+
+    /// past expires_at, anyone can prune the sale
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at.0)
+    }
+
+    /// inside the listing window: started and not expired
+    pub fn is_listed(&self, now: u64) -> bool {
+        self.starts_at.map_or(true, |starts_at| now >= starts_at.0) && !self.is_expired(now)
+    }
+
+    /// purchases and bids are only taken inside the listing window
+    pub fn assert_listed(&self, now: u64) {
+        if let Some(starts_at) = self.starts_at {
+            assert!(now >= starts_at.0, "Sale has not started");
+        }
+        assert!(!self.is_expired(now), "Sale has expired");
+    }
+
+    /// panics outside of a timed auction's start and end
+    pub fn assert_auction_open(&self, now: u64) {
+        if let Some(auction_start_at) = self.auction_start_at {
+            assert!(now >= auction_start_at.0, "Auction has not started");
+        }
+        if let Some(auction_end_at) = self.auction_end_at {
+            assert!(now < auction_end_at.0, "Auction has ended");
+        }
+    }
+
+    pub fn sealed_bid_phase(&self, now: u64) -> Option<SealedBidPhase> {
+        self.sealed_bid.as_ref().map(|sealed_bid| {
+            if now < sealed_bid.commit_end_at.0 {
+                SealedBidPhase::Commit
+            } else if now < sealed_bid.reveal_end_at.0 {
+                SealedBidPhase::Reveal
+            } else {
+                SealedBidPhase::Ended
+            }
+        })
+    }
+
+    /// price in ft_token_id at time now (ms), None if not for sale in that token
+    pub fn current_price(&self, ft_token_id: &str, now: u64) -> Option<Balance> {
+        let price = self.sale_conditions.get(ft_token_id)?.0;
+        let dutch_auction = if let Some(dutch_auction) = &self.dutch_auction {
+            dutch_auction
+        } else {
+            return Some(price);
+        };
+        let floor = dutch_auction.floor_conditions.get(ft_token_id)?.0;
+        let (start_at, end_at) = (dutch_auction.start_at.0, dutch_auction.end_at.0);
+        if now <= start_at {
+            Some(price)
+        } else if now >= end_at {
+            Some(floor)
+        } else {
+            // (price - floor) * elapsed / duration without overflowing u128
+            let (drop, elapsed, duration) = (
+                price - floor,
+                (now - start_at) as u128,
+                (end_at - start_at) as u128,
+            );
+            let decrease = drop / duration * elapsed + drop % duration * elapsed / duration;
+            Some(price - decrease)
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,6 +296,18 @@ pub struct Sale {
 pub struct PurchaseArgs {
     pub nft_contract_id: ValidAccountId,
     pub token_id: TokenId,
+    /// commit a sealed bid instead of buying or bidding
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sealed_bid_hash: Option<Base64VecU8>,
+    /// expiry of the bid if the payment doesn't buy the token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<U64>,
+    /// top up the sender's bid to the seller's counter-offer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accept_counter_offer: Option<bool>,
+    /// the frontend that brought the purchase, paid the market's referral share
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer_id: Option<ValidAccountId>,
 }
 
 #[near_bindgen]
@@ -39,7 +320,8 @@ impl Contract {
         let sale = self.internal_remove_sale(nft_contract_id.into(), token_id);
         let owner_id = env::predecessor_account_id();
         assert_eq!(owner_id, sale.owner_id, "Must be sale owner");
-        self.refund_all_bids(&sale.bids);
+        self.refund_all_bids(&sale.contract_and_token_id(), &sale.bids);
+        self.refund_sealed_bids(&sale);
     }
 
     #[payable]
@@ -62,93 +344,174 @@ impl Contract {
         if !self.ft_token_ids.contains(ft_token_id.as_ref()) {
             env::panic(format!("Token {} not supported by this market", ft_token_id).as_bytes());
         }
+        assert!(
+            sale.dutch_auction.is_none(),
+            "Cannot update the price of a Dutch auction"
+        );
+        assert!(
+            sale.sealed_bid.is_none(),
+            "Cannot update the price of a sealed-bid auction"
+        );
+        if let Some(buy_now_price) = sale.buy_now_price(ft_token_id.as_ref()) {
+            assert!(
+                price.0 <= buy_now_price,
+                "Price can't be more than the buy-now price"
+            );
+        }
+        if sale.auction_end_at.is_some() {
+            assert!(
+                sale.sale_conditions.contains_key(ft_token_id.as_ref()),
+                "Timed auctions must be listed in exactly one token"
+            );
+        }
+        MarketEvent::SaleUpdated(vec![SaleUpdatedData {
+            nft_contract_id: &contract_id,
+            token_id: &token_id,
+            ft_token_id: ft_token_id.as_ref(),
+            price,
+        }])
+        .emit();
+        self.internal_remove_sale_prices(&sale);
         sale.sale_conditions.insert(ft_token_id.into(), price);
+        self.internal_add_sale_prices(&sale);
         self.sales.insert(&contract_and_token_id, &sale);
     }
 
+    /// buy or bid on a listed token, expires_at applies if the deposit becomes a bid rather
+    /// than buying the token
     #[payable]
-    pub fn offer(&mut self, nft_contract_id: ValidAccountId, token_id: String) {
-        let contract_id: AccountId = nft_contract_id.into();
-        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
-        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
-        let buyer_id = env::predecessor_account_id();
-        assert_ne!(sale.owner_id, buyer_id, "Cannot bid on your own sale.");
-        let ft_token_id = "near".to_string();
-        let price = sale
-            .sale_conditions
-            .get(&ft_token_id)
-            .expect("Not for sale in NEAR")
-            .0;
-
+    pub fn offer(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_id: String,
+        expires_at: Option<U64>,
+        referrer_id: Option<ValidAccountId>,
+    ) {
         let deposit = env::attached_deposit();
         assert!(deposit > 0, "Attached deposit must be greater than 0");
-
-        if !sale.is_auction && deposit == price {
-            self.process_purchase(contract_id, token_id, ft_token_id, U128(deposit), buyer_id);
-        } else {
-            if sale.is_auction && price > 0 {
-                assert!(
-                    deposit >= price,
-                    "Attached deposit must be greater than reserve price"
-                );
-            }
-            self.add_bid(
-                contract_and_token_id,
-                deposit,
-                ft_token_id,
-                buyer_id,
-                &mut sale,
-            );
-        }
+        self.internal_offer(
+            nft_contract_id.into(),
+            token_id,
+            "near".to_string(),
+            deposit,
+            env::predecessor_account_id(),
+            expires_at,
+            referrer_id.map(|referrer_id| referrer_id.into()),
+        );
     }
 
     #[private]
+    #[allow(clippy::too_many_arguments)]
     pub fn add_bid(
         &mut self,
         contract_and_token_id: ContractAndTokenId,
         amount: Balance,
         ft_token_id: AccountId,
         buyer_id: AccountId,
+        expires_at: Option<U64>,
+        referrer_id: Option<AccountId>,
         sale: &mut Sale,
     ) {
+        assert!(sale.is_buyer_allowed(&buyer_id), "Private sale");
+        let now = current_time_ms();
+        sale.assert_listed(now);
+        sale.assert_auction_open(now);
+        if let Some(expires_at) = expires_at {
+            assert!(expires_at.0 > now, "Bid expiry must be in the future");
+            // a top bid that lapses before the end would leave the auction without one
+            if let Some(auction_end_at) = sale.auction_end_at {
+                assert!(
+                    expires_at.0 >= auction_end_at.0,
+                    "Bid can't expire before the auction ends"
+                );
+            }
+        }
+        if let Some(buy_now_price) = sale.buy_now_price(&ft_token_id) {
+            assert!(
+                amount < buy_now_price,
+                "Bids can't reach the buy-now price {}",
+                buy_now_price
+            );
+        }
+
         // store a bid and refund any current bid lower
         let new_bid = Bid {
-            owner_id: buyer_id,
+            owner_id: buyer_id.clone(),
             price: U128(amount),
+            expires_at,
+            referrer_id,
         };
 
+        let min_next_bid = self.internal_min_next_bid(sale, &ft_token_id);
+        assert!(
+            amount >= min_next_bid,
+            "Bid must be at least {}",
+            min_next_bid
+        );
+
+        sale.counter_offers.remove(&ft_token_id);
         let bids_for_token_id = sale
             .bids
             .entry(ft_token_id.clone())
             .or_insert_with(Vec::new);
 
-        if !bids_for_token_id.is_empty() {
-            let current_bid = &bids_for_token_id[bids_for_token_id.len() - 1];
-            assert!(
-                amount > current_bid.price.0,
-                "Can't pay less than or equal to current bid price: {}",
-                current_bid.price.0
-            );
-            if ft_token_id == "near" {
-                Promise::new(current_bid.owner_id.clone()).transfer(u128::from(current_bid.price));
-            } else {
-                ext_contract::ft_transfer(
-                    current_bid.owner_id.clone(),
-                    current_bid.price,
-                    None,
-                    &ft_token_id,
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
-            }
-        }
+        // only the newest bid is escrowed, older ones were refunded when outbid
+        let outbid_id = bids_for_token_id.last().map(|current_bid| {
+            transfer_to(&ft_token_id, &current_bid.owner_id, current_bid.price);
+            MarketEvent::BidRefunded(vec![BidData::new(
+                &contract_and_token_id,
+                &current_bid.owner_id,
+                &ft_token_id,
+                current_bid.price,
+            )])
+            .emit();
+            current_bid.owner_id.clone()
+        });
+        MarketEvent::BidPlaced(vec![BidData::new(
+            &contract_and_token_id,
+            &buyer_id,
+            &ft_token_id,
+            U128(amount),
+        )])
+        .emit();
 
         bids_for_token_id.push(new_bid);
         if bids_for_token_id.len() > self.bid_history_length as usize {
             bids_for_token_id.remove(0);
         }
+        if let Some(outbid_id) = outbid_id {
+            self.internal_remove_bid_index(&outbid_id, &contract_and_token_id, &ft_token_id);
+        }
+        self.internal_add_bid_index(&buyer_id, &contract_and_token_id, &ft_token_id);
 
-        self.sales.insert(&contract_and_token_id, &sale);
+        // anti-sniping, a late bid extends the auction
+        if let (Some(auction_end_at), Some(extension_window)) =
+            (sale.auction_end_at, sale.extension_window)
+        {
+            if auction_end_at.0 - now < extension_window.0 {
+                let mut new_end_at = now + extension_window.0;
+                if let Some(max_extension) = sale.max_extension {
+                    let original_end_at = auction_end_at.0 - sale.total_extension.0;
+                    new_end_at = min(new_end_at, original_end_at + max_extension.0);
+                }
+                if let Some(expires_at) = sale.expires_at {
+                    new_end_at = min(new_end_at, expires_at.0);
+                }
+                if new_end_at > auction_end_at.0 {
+                    sale.total_extension =
+                        U64(sale.total_extension.0 + new_end_at - auction_end_at.0);
+                    sale.auction_end_at = Some(U64(new_end_at));
+                    MarketEvent::AuctionExtended(vec![AuctionExtendedData {
+                        nft_contract_id: &sale.nft_contract_id,
+                        token_id: &sale.token_id,
+                        auction_end_at: U64(new_end_at),
+                    }])
+                    .emit();
+                }
+            }
+        }
+
+        self.sales.insert(&contract_and_token_id, sale);
     }
 
     pub fn accept_offer(
@@ -158,23 +521,291 @@ impl Contract {
         ft_token_id: ValidAccountId,
     ) {
         let contract_id: AccountId = nft_contract_id.into();
-        let contract_and_token_id =
-            format!("{}{}{}", contract_id.clone(), DELIMETER, token_id.clone());
+        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
         // remove bid before proceeding to process purchase
         let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        // the seller may accept a bid below the reserve price
+        assert_eq!(
+            env::predecessor_account_id(),
+            sale.owner_id,
+            "Must be sale owner"
+        );
+        let now = current_time_ms();
+        sale.assert_listed(now);
         let bids_for_token_id = sale.bids.remove(ft_token_id.as_ref()).expect("No bids");
         let bid = &bids_for_token_id[bids_for_token_id.len() - 1];
+        assert!(!bid.is_expired(now), "Bid has expired");
+        self.internal_remove_bid_index(&bid.owner_id, &contract_and_token_id, ft_token_id.as_ref());
         self.sales.insert(&contract_and_token_id, &sale);
-        // panics at `self.internal_remove_sale` and reverts above if predecessor is not sale.owner_id
         self.process_purchase(
             contract_id,
             token_id,
             ft_token_id.into(),
             bid.price,
             bid.owner_id.clone(),
+            bid.referrer_id.clone(),
         );
     }
 
+    /// the current bidder takes back their escrow, except in a timed auction's final window
+    #[payable]
+    pub fn withdraw_bid(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_id: String,
+        ft_token_id: ValidAccountId,
+    ) {
+        assert_one_yocto();
+        let contract_id: AccountId = nft_contract_id.into();
+        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        if let Some(auction_end_at) = sale.auction_end_at {
+            let final_window = sale
+                .extension_window
+                .map_or(AUCTION_FINAL_WINDOW, |window| window.0);
+            assert!(
+                current_time_ms() + final_window < auction_end_at.0,
+                "Bids can't be withdrawn in the auction's final window"
+            );
+        }
+        let bids_for_token_id = sale.bids.remove(ft_token_id.as_ref()).expect("No bids");
+        let bid = &bids_for_token_id[bids_for_token_id.len() - 1];
+        assert_eq!(
+            env::predecessor_account_id(),
+            bid.owner_id,
+            "Only the current bid can be withdrawn"
+        );
+        transfer_to(ft_token_id.as_ref(), &bid.owner_id, bid.price);
+        MarketEvent::BidRefunded(vec![BidData::new(
+            &contract_and_token_id,
+            &bid.owner_id,
+            ft_token_id.as_ref(),
+            bid.price,
+        )])
+        .emit();
+        self.internal_remove_bid_index(&bid.owner_id, &contract_and_token_id, ft_token_id.as_ref());
+        sale.counter_offers.remove(ft_token_id.as_ref());
+        self.sales.insert(&contract_and_token_id, &sale);
+    }
+
+    /// anyone can refund the expired bids of a sale
+    pub fn remove_expired_bids(&mut self, nft_contract_id: ValidAccountId, token_id: String) {
+        let contract_id: AccountId = nft_contract_id.into();
+        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        let now = current_time_ms();
+        let expired_fts: Vec<FungibleTokenId> = sale
+            .bids
+            .iter()
+            .filter(|(_, bids)| bids[bids.len() - 1].is_expired(now))
+            .map(|(ft_token_id, _)| ft_token_id.clone())
+            .collect();
+        assert!(!expired_fts.is_empty(), "No expired bids");
+        let expired_bids: Bids = expired_fts
+            .into_iter()
+            .map(|ft_token_id| {
+                let bids = sale.bids.remove(&ft_token_id).unwrap();
+                sale.counter_offers.remove(&ft_token_id);
+                (ft_token_id, bids)
+            })
+            .collect();
+        self.refund_all_bids(&contract_and_token_id, &expired_bids);
+        self.sales.insert(&contract_and_token_id, &sale);
+    }
+
+    /// the seller asks the current bidder in ft_token_id for a higher price,
+    /// replacing any earlier counter-offer in that token
+    #[payable]
+    pub fn counter_offer(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_id: String,
+        ft_token_id: ValidAccountId,
+        price: U128,
+        expires_at: Option<U64>,
+    ) {
+        assert_one_yocto();
+        let contract_id: AccountId = nft_contract_id.into();
+        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        assert_eq!(
+            env::predecessor_account_id(),
+            sale.owner_id,
+            "Must be sale owner"
+        );
+        let now = current_time_ms();
+        if let Some(expires_at) = expires_at {
+            assert!(
+                expires_at.0 > now,
+                "Counter-offer expiry must be in the future"
+            );
+        }
+        let bids_for_token_id = sale.bids.get(ft_token_id.as_ref()).expect("No bids");
+        let bid = &bids_for_token_id[bids_for_token_id.len() - 1];
+        assert!(!bid.is_expired(now), "Bid has expired");
+        assert!(
+            price.0 > bid.price.0,
+            "Counter-offer must be more than the bid, use accept_offer instead"
+        );
+        let counter_offer = CounterOffer {
+            bidder_id: bid.owner_id.clone(),
+            price,
+            expires_at,
+        };
+        sale.counter_offers
+            .insert(ft_token_id.into(), counter_offer);
+        self.sales.insert(&contract_and_token_id, &sale);
+    }
+
+    /// the bidder accepts a counter-offer in NEAR by attaching the difference,
+    /// FT bidders accept through ft_on_transfer
+    #[payable]
+    pub fn accept_counter_offer(&mut self, nft_contract_id: ValidAccountId, token_id: String) {
+        let deposit = env::attached_deposit();
+        assert!(deposit > 0, "Attached deposit must be greater than 0");
+        self.internal_accept_counter_offer(
+            nft_contract_id.into(),
+            token_id,
+            "near".to_string(),
+            deposit,
+            env::predecessor_account_id(),
+        );
+    }
+
+    /// anyone can remove the expired sales among limit sales from from_index, refunding their
+    /// bids and releasing the storage their owners were charged. Returns how many were removed
+    pub fn prune_expired_sales(&mut self, from_index: U64, limit: u64) -> U64 {
+        let now = current_time_ms();
+        let keys = self.sales.keys_as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + limit, keys.len());
+        // removing swaps sales around, so collect before removing
+        let expired_sales: Vec<(AccountId, TokenId)> = (start..end)
+            .filter_map(|i| self.sales.get(&keys.get(i).unwrap()))
+            .filter(|sale| sale.is_expired(now))
+            .map(|sale| (sale.nft_contract_id, sale.token_id))
+            .collect();
+        for (nft_contract_id, token_id) in &expired_sales {
+            let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
+            self.refund_all_bids(&sale.contract_and_token_id(), &sale.bids);
+            self.refund_sealed_bids(&sale);
+        }
+        U64(expired_sales.len() as u64)
+    }
+
+    /// escrow a sealed bid in NEAR, hash is sha256 of "{price}:{salt}" and the deposit
+    /// must cover the price, FTs are committed through ft_on_transfer
+    #[payable]
+    pub fn commit_bid(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_id: String,
+        hash: Base64VecU8,
+        referrer_id: Option<ValidAccountId>,
+    ) {
+        let deposit = env::attached_deposit();
+        assert!(deposit > 0, "Attached deposit must be greater than 0");
+        self.internal_commit_bid(
+            nft_contract_id.into(),
+            token_id,
+            "near".to_string(),
+            deposit,
+            env::predecessor_account_id(),
+            hash,
+            referrer_id.map(|referrer_id| referrer_id.into()),
+        );
+    }
+
+    /// open a committed bid during the reveal phase
+    pub fn reveal_bid(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_id: String,
+        price: U128,
+        salt: String,
+    ) {
+        let contract_id: AccountId = nft_contract_id.into();
+        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        assert_eq!(
+            sale.sealed_bid_phase(current_time_ms()),
+            Some(SealedBidPhase::Reveal),
+            "Not in the reveal phase"
+        );
+        let reserve_price = sale.sale_conditions.values().next().unwrap().0;
+        let bidder_id = env::predecessor_account_id();
+        let sealed_bid = sale
+            .sealed_bid
+            .as_mut()
+            .unwrap()
+            .commitments
+            .iter_mut()
+            .find(|sealed_bid| sealed_bid.owner_id == bidder_id)
+            .expect("No committed bid");
+        assert!(sealed_bid.price.is_none(), "Bid already revealed");
+        let hash = env::sha256(format!("{}:{}", price.0, salt).as_bytes());
+        assert_eq!(
+            hash, sealed_bid.hash.0,
+            "Price and salt don't match the commitment"
+        );
+        assert!(
+            price.0 <= sealed_bid.deposit.0,
+            "Revealed price is more than the deposit"
+        );
+        assert!(
+            price.0 >= reserve_price,
+            "Revealed price is less than the reserve price"
+        );
+        sealed_bid.price = Some(price);
+        self.sales.insert(&contract_and_token_id, &sale);
+    }
+
+    /// anyone can settle a timed auction once it has ended: the top bid buys the token if it
+    /// meets the reserve, otherwise the sale is closed and its storage released to the owner
+    pub fn settle_auction(&mut self, nft_contract_id: ValidAccountId, token_id: String) {
+        let contract_id: AccountId = nft_contract_id.into();
+        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        if sale.sealed_bid.is_some() {
+            return self.internal_settle_sealed_bid(contract_id, token_id, sale);
+        }
+        let auction_end_at = sale.auction_end_at.expect("Sale is not a timed auction");
+        assert!(
+            current_time_ms() >= auction_end_at.0,
+            "Auction has not ended"
+        );
+        // timed auctions are listed in exactly one token, see nft_on_approve
+        let ft_token_id = sale
+            .sale_conditions
+            .keys()
+            .next()
+            .cloned()
+            .expect("No sale conditions");
+        let reserve_price = sale.reserve_price(&ft_token_id);
+        let now = current_time_ms();
+        let reserve_met = sale.bids.get(&ft_token_id).map_or(false, |bids| {
+            let bid = &bids[bids.len() - 1];
+            bid.price.0 >= reserve_price && !bid.is_expired(now)
+        });
+        if reserve_met {
+            let bids_for_token_id = sale.bids.remove(&ft_token_id).unwrap();
+            let bid = &bids_for_token_id[bids_for_token_id.len() - 1];
+            self.internal_remove_bid_index(&bid.owner_id, &contract_and_token_id, &ft_token_id);
+            self.sales.insert(&contract_and_token_id, &sale);
+            self.process_purchase(
+                contract_id,
+                token_id,
+                ft_token_id,
+                bid.price,
+                bid.owner_id.clone(),
+                bid.referrer_id.clone(),
+            );
+        } else {
+            let sale = self.internal_remove_sale(contract_id, token_id);
+            self.refund_all_bids(&contract_and_token_id, &sale.bids);
+        }
+    }
+
     #[private]
     pub fn process_purchase(
         &mut self,
@@ -183,28 +814,10 @@ impl Contract {
         ft_token_id: AccountId,
         price: U128,
         buyer_id: AccountId,
+        referrer_id: Option<AccountId>,
     ) -> Promise {
-        let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
-
-        ext_contract::nft_transfer_payout(
-            buyer_id.clone(),
-            token_id,
-            sale.approval_id,
-            None,
-            price,
-            &nft_contract_id,
-            1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .then(ext_self::resolve_purchase(
-            ft_token_id,
-            buyer_id,
-            sale,
-            price,
-            &env::current_account_id(),
-            NO_DEPOSIT,
-            GAS_FOR_ROYALTIES,
-        ))
+        let sale = self.internal_remove_sale(nft_contract_id, token_id);
+        self.internal_nft_transfer_payout(sale, ft_token_id, price, buyer_id, referrer_id)
     }
 
     /// self callback
@@ -216,81 +829,65 @@ impl Contract {
         buyer_id: AccountId,
         sale: Sale,
         price: U128,
+        referrer_id: Option<AccountId>,
     ) -> U128 {
+        let referral_receivers = usize::from(self.referral_fee_bps(referrer_id.as_ref()) > 0);
         // checking for payout information
         let payout_option = promise_result_as_success().and_then(|value| {
             // None means a bad payout from bad NFT contract
-            near_sdk::serde_json::from_slice::<Payout>(&value)
-                .ok()
-                .and_then(|payout| {
-                    // gas to do 10 FT transfers (and definitely 10 NEAR transfers)
-                    if payout.len() + sale.bids.len() > 10 || payout.is_empty() {
-                        env::log(
-                            format!("Cannot have more than 10 royalties and sale.bids refunds")
-                                .as_bytes(),
-                        );
-                        None
-                    } else {
-                        // TODO off by 1 e.g. payouts are fractions of 3333 + 3333 + 3333
-                        let mut remainder = price.0;
-                        for &value in payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder == 0 || remainder == 1 {
-                            Some(payout)
-                        } else {
-                            None
-                        }
-                    }
-                })
+            parse_payout(&value, price.0).filter(|payout| {
+                // gas to do 10 FT transfers (and definitely 10 NEAR transfers)
+                if payout.len() + sale.bids.len() + referral_receivers > 10 {
+                    env::log(
+                        "Cannot have more than 10 royalties, referrer and sale.bids refunds"
+                            .as_bytes(),
+                    );
+                    false
+                } else {
+                    true
+                }
+            })
         });
+        // the sale is gone either way, so return all outstanding bids (accepted offer bid was already removed)
+        self.refund_all_bids(&sale.contract_and_token_id(), &sale.bids);
+        let held_bytes = sale.trade_storage_max();
+        let initial_storage_usage = env::storage_usage();
+        let mut purchase = PurchaseData {
+            owner_id: &sale.owner_id,
+            buyer_id: &buyer_id,
+            nft_contract_id: &sale.nft_contract_id,
+            token_id: &sale.token_id,
+            bundle_id: None,
+            ft_token_id: &ft_token_id,
+            price,
+            paid_out: None,
+        };
         // is payout option valid?
         let payout = if let Some(payout_option) = payout_option {
             payout_option
         } else {
-            if ft_token_id == "near" {
-                Promise::new(buyer_id).transfer(u128::from(price));
-            }
-            // leave function and return all FTs in ft_resolve_transfer
+            // the market escrows NEAR and FTs alike, refund the buyer
+            transfer_to(&ft_token_id, &buyer_id, price);
+            MarketEvent::PurchaseFailed(vec![purchase]).emit();
+            self.internal_settle_held_storage(&sale.owner_id, held_bytes, initial_storage_usage);
             return price;
         };
-        // Going to payout everyone, first return all outstanding bids (accepted offer bid was already removed)
-        self.refund_all_bids(&sale.bids);
 
-        // NEAR payouts
-        if ft_token_id == "near" {
-            for (receiver_id, amount) in payout {
-                Promise::new(receiver_id).transfer(amount.0);
-            }
-            // refund all FTs (won't be any)
-            price
-        } else {
-            // FT payouts
-            for (receiver_id, amount) in payout {
-                ext_contract::ft_transfer(
-                    receiver_id,
-                    amount,
-                    None,
-                    &ft_token_id,
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
-            }
-            // keep all FTs (already transferred for payouts)
-            U128(0)
-        }
+        purchase.paid_out = Some(self.internal_pay_out(&ft_token_id, payout, price.0, referrer_id));
+        MarketEvent::PurchaseSucceeded(vec![purchase]).emit();
+        let trade = Trade {
+            nft_contract_id: sale.nft_contract_id,
+            token_id: sale.token_id,
+            seller_id: sale.owner_id.clone(),
+            buyer_id,
+            ft_token_id,
+            price,
+            traded_at: U64(current_time_ms()),
+        };
+        self.internal_record_stats(&trade, sale.token_type.as_ref());
+        self.internal_record_trade(trade);
+        self.internal_settle_held_storage(&sale.owner_id, held_bytes, initial_storage_usage);
+        // nothing refunded to the buyer
+        U128(0)
     }
 }
-
-/// self call
-
-#[ext_contract(ext_self)]
-trait ExtSelf {
-    fn resolve_purchase(
-        &mut self,
-        ft_token_id: AccountId,
-        buyer_id: AccountId,
-        sale: Sale,
-        price: U128,
-    ) -> Promise;
-}
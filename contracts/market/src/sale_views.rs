@@ -1,7 +1,145 @@
 use crate::*;
+use std::ops::Bound;
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BidderBid {
+    pub nft_contract_token: ContractAndTokenId,
+    pub ft_token_id: FungibleTokenId,
+    pub bid: Bid,
+}
+
+/// filters of get_sales, all optional and combined with and. Prices are current prices in
+/// ft_token_id, which price filters require
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleQuery {
+    pub owner_id: Option<AccountId>,
+    pub nft_contract_id: Option<AccountId>,
+    pub token_type: Option<String>,
+    pub ft_token_id: Option<AccountId>,
+    pub min_price: Option<U128>,
+    pub max_price: Option<U128>,
+    pub is_auction: Option<bool>,
+    pub min_created_at: Option<U64>,
+    pub max_created_at: Option<U64>,
+    /// private sales are left out unless viewer_id may buy them
+    pub viewer_id: Option<AccountId>,
+    /// next_cursor of the previous page, results start after this sale
+    pub cursor: Option<ListingCursor>,
+}
+
+impl SaleQuery {
+    fn matches(&self, sale: &Sale, now: u64) -> bool {
+        if self
+            .owner_id
+            .as_ref()
+            .map_or(false, |id| id != &sale.owner_id)
+            || self
+                .nft_contract_id
+                .as_ref()
+                .map_or(false, |id| id != &sale.nft_contract_id)
+            || self.token_type.as_ref().map_or(false, |token_type| {
+                sale.token_type.as_ref() != Some(token_type)
+            })
+            || self
+                .is_auction
+                .map_or(false, |is_auction| is_auction != sale.is_auction)
+            || self
+                .min_created_at
+                .map_or(false, |created_at| sale.created_at.0 < created_at.0)
+            || self
+                .max_created_at
+                .map_or(false, |created_at| sale.created_at.0 > created_at.0)
+            || !sale.is_visible_to(self.viewer_id.as_ref())
+        {
+            return false;
+        }
+        let ft_token_id = if let Some(ft_token_id) = &self.ft_token_id {
+            ft_token_id
+        } else {
+            return true;
+        };
+        let price = if let Some(price) = sale.current_price(ft_token_id, now) {
+            price
+        } else {
+            return false;
+        };
+        self.min_price
+            .map_or(true, |min_price| price >= min_price.0)
+            && self
+                .max_price
+                .map_or(true, |max_price| price <= max_price.0)
+    }
+}
+
+/// next_cursor is None once there are no more sales to look at
+#[derive(Serialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SalesPage {
+    pub sales: Vec<Sale>,
+    pub next_cursor: Option<ListingCursor>,
+}
 
 #[near_bindgen]
 impl Contract {
+    /// up to limit sales matching query, oldest listing first. A page looks at no more than
+    /// SALE_QUERY_SCAN_MAX sales, so it can be short and still have a next_cursor. Pages
+    /// don't shift when sales are removed, a limit of 0 looks at none
+    pub fn get_sales(&self, query: SaleQuery, limit: u64) -> SalesPage {
+        assert!(
+            query.ft_token_id.is_some() || (query.min_price.is_none() && query.max_price.is_none()),
+            "Price filters require ft_token_id"
+        );
+        let now = current_time_ms();
+        // walk the smallest index the query names
+        let group_listings = if let Some(owner_id) = &query.owner_id {
+            Some(self.by_owner_id.get(owner_id))
+        } else if let Some(token_type) = &query.token_type {
+            Some(self.by_nft_token_type.get(token_type))
+        } else {
+            query
+                .nft_contract_id
+                .as_ref()
+                .map(|nft_contract_id| self.by_nft_contract_id.get(nft_contract_id))
+        };
+        let listings = match &group_listings {
+            Some(Some(listings)) => listings,
+            Some(None) => return SalesPage::default(),
+            None => &self.listings,
+        };
+        // "" sorts before every sale key
+        let start = query.cursor.clone().map_or_else(
+            || Bound::Included((query.min_created_at.map_or(0, |at| at.0), String::new())),
+            |(created_at, key)| Bound::Excluded((created_at.0, key)),
+        );
+        let max_created_at = query.max_created_at.map_or(u64::MAX, |at| at.0);
+        let mut keys = listings
+            .range((start, Bound::Unbounded))
+            .map(|(entry, _)| entry)
+            .take_while(|(created_at, _)| *created_at <= max_created_at)
+            .peekable();
+
+        let mut sales = vec![];
+        let mut last_entry = query.cursor.clone();
+        let mut scanned = 0;
+        while (sales.len() as u64) < limit && scanned < SALE_QUERY_SCAN_MAX {
+            let (created_at, key) = if let Some(entry) = keys.next() {
+                entry
+            } else {
+                break;
+            };
+            let sale = self.sales.get(&key).unwrap();
+            if query.matches(&sale, now) {
+                sales.push(sale.public_view());
+            }
+            scanned += 1;
+            last_entry = Some((U64(created_at), key));
+        }
+        let next_cursor = last_entry.filter(|_| keys.peek().is_some());
+        SalesPage { sales, next_cursor }
+    }
+
     /// views
     pub fn get_supply_sales(&self) -> U64 {
         U64(self.sales.len())
@@ -16,24 +154,86 @@ impl Contract {
         }
     }
 
+    /// sale lists leave out private sales unless viewer_id may buy them
     pub fn get_sales_by_owner_id(
         &self,
         account_id: AccountId,
         from_index: U64,
         limit: u64,
+        viewer_id: Option<AccountId>,
     ) -> Vec<Sale> {
+        self.sales_from_index(
+            self.by_owner_id.get(&account_id),
+            from_index,
+            limit,
+            viewer_id.as_ref(),
+        )
+    }
+
+    /// bid history of a sale in ft_token_id, oldest first, only the last bid is escrowed
+    pub fn get_bids(
+        &self,
+        nft_contract_token: ContractAndTokenId,
+        ft_token_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Bid> {
+        let mut sale = if let Some(sale) = self.sales.get(&nft_contract_token) {
+            sale
+        } else {
+            return vec![];
+        };
+        sale.bids
+            .remove(&ft_token_id)
+            .unwrap_or_default()
+            .into_iter()
+            .skip(from_index.0 as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_supply_by_bidder_id(&self, account_id: AccountId) -> U64 {
+        let by_bidder_id = self.by_bidder_id.get(&account_id);
+        if let Some(by_bidder_id) = by_bidder_id {
+            U64(by_bidder_id.len())
+        } else {
+            U64(0)
+        }
+    }
+
+    /// escrowed bids of an account
+    pub fn get_bids_by_bidder(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<BidderBid> {
         let mut tmp = vec![];
-        let by_owner_id = self.by_owner_id.get(&account_id);
-        let sales = if let Some(by_owner_id) = by_owner_id {
-            by_owner_id
+        let by_bidder_id = self.by_bidder_id.get(&account_id);
+        let bids = if let Some(by_bidder_id) = by_bidder_id {
+            by_bidder_id
         } else {
             return vec![];
         };
-        let keys = sales.as_vector();
+        let keys = bids.as_vector();
         let start = u64::from(from_index);
-        let end = min(start + limit, sales.len());
+        let end = min(start + limit, bids.len());
         for i in start..end {
-            tmp.push(self.sales.get(&keys.get(i).unwrap()).unwrap());
+            let key = keys.get(i).unwrap();
+            let (nft_contract_token, ft_token_id) = key.rsplit_once(DELIMETER).unwrap();
+            // a sale being purchased is already gone until resolve_purchase refunds its bids
+            let bid = self
+                .sales
+                .get(&nft_contract_token.to_string())
+                .and_then(|mut sale| sale.bids.remove(ft_token_id))
+                .and_then(|mut bids| bids.pop());
+            if let Some(bid) = bid {
+                tmp.push(BidderBid {
+                    nft_contract_token: nft_contract_token.to_string(),
+                    ft_token_id: ft_token_id.to_string(),
+                    bid,
+                });
+            }
         }
         tmp
     }
@@ -52,30 +252,14 @@ impl Contract {
         nft_contract_id: AccountId,
         from_index: U64,
         limit: u64,
+        viewer_id: Option<AccountId>,
     ) -> Vec<Sale> {
-        let mut tmp = vec![];
-        let by_nft_contract_id = self.by_nft_contract_id.get(&nft_contract_id);
-        let sales = if let Some(by_nft_contract_id) = by_nft_contract_id {
-            by_nft_contract_id
-        } else {
-            return vec![];
-        };
-        let keys = sales.as_vector();
-        let start = u64::from(from_index);
-        let end = min(start + limit, sales.len());
-        for i in start..end {
-            tmp.push(
-                self.sales
-                    .get(&format!(
-                        "{}{}{}",
-                        &nft_contract_id,
-                        DELIMETER,
-                        &keys.get(i).unwrap()
-                    ))
-                    .unwrap(),
-            );
-        }
-        tmp
+        self.sales_from_index(
+            self.by_nft_contract_id.get(&nft_contract_id),
+            from_index,
+            limit,
+            viewer_id.as_ref(),
+        )
     }
 
     pub fn get_supply_by_nft_token_type(&self, token_type: String) -> U64 {
@@ -92,24 +276,160 @@ impl Contract {
         token_type: String,
         from_index: U64,
         limit: u64,
+        viewer_id: Option<AccountId>,
     ) -> Vec<Sale> {
-        let mut tmp = vec![];
-        let by_nft_token_type = self.by_nft_token_type.get(&token_type);
-        let sales = if let Some(by_nft_token_type) = by_nft_token_type {
-            by_nft_token_type
+        self.sales_from_index(
+            self.by_nft_token_type.get(&token_type),
+            from_index,
+            limit,
+            viewer_id.as_ref(),
+        )
+    }
+
+    pub fn get_sale(
+        &self,
+        nft_contract_token: ContractAndTokenId,
+        viewer_id: Option<AccountId>,
+    ) -> Option<Sale> {
+        self.sales
+            .get(&nft_contract_token)
+            .filter(|sale| sale.is_visible_to(viewer_id.as_ref()))
+            .map(Sale::public_view)
+    }
+
+    pub fn get_min_next_bid(
+        &self,
+        nft_contract_token: ContractAndTokenId,
+        ft_token_id: AccountId,
+    ) -> U128 {
+        let sale = self.sales.get(&nft_contract_token).expect("No sale");
+        U128(self.internal_min_next_bid(&sale, &ft_token_id))
+    }
+
+    /// whether the top bid in ft_token_id would win when the auction is settled
+    pub fn is_reserve_met(
+        &self,
+        nft_contract_token: ContractAndTokenId,
+        ft_token_id: AccountId,
+    ) -> bool {
+        let sale = self.sales.get(&nft_contract_token).expect("No sale");
+        let reserve_price = sale.reserve_price(&ft_token_id);
+        sale.bids
+            .get(&ft_token_id)
+            .map_or(false, |bids| bids[bids.len() - 1].price.0 >= reserve_price)
+    }
+
+    pub fn get_sealed_bid_phase(
+        &self,
+        nft_contract_token: ContractAndTokenId,
+    ) -> Option<SealedBidPhase> {
+        let sale = self.sales.get(&nft_contract_token).expect("No sale");
+        sale.sealed_bid_phase(current_time_ms())
+    }
+
+    /// live price per token, Dutch auction prices fall over time
+    pub fn get_current_price(&self, nft_contract_token: ContractAndTokenId) -> SaleConditions {
+        let sale = self.sales.get(&nft_contract_token).expect("No sale");
+        let now = current_time_ms();
+        sale.sale_conditions
+            .keys()
+            .map(|ft_token_id| {
+                let price = sale.current_price(ft_token_id, now).unwrap();
+                (ft_token_id.clone(), U128(price))
+            })
+            .collect()
+    }
+
+    /// newest sales first, of one collection if nft_contract_id is set. Looks at no more than
+    /// SALE_QUERY_SCAN_MAX sales
+    pub fn get_newest_sales(
+        &self,
+        nft_contract_id: Option<AccountId>,
+        limit: u64,
+        viewer_id: Option<AccountId>,
+    ) -> Vec<Sale> {
+        let by_nft_contract_id;
+        let listings = if let Some(nft_contract_id) = nft_contract_id {
+            match self.by_nft_contract_id.get(&nft_contract_id) {
+                Some(listings) => {
+                    by_nft_contract_id = listings;
+                    &by_nft_contract_id
+                }
+                None => return vec![],
+            }
         } else {
-            return vec![];
+            &self.listings
         };
-        let keys = sales.as_vector();
-        let start = u64::from(from_index);
-        let end = min(start + limit, sales.len());
-        for i in start..end {
-            tmp.push(self.sales.get(&keys.get(i).unwrap()).unwrap());
-        }
-        tmp
+        listings
+            .iter_rev()
+            .take(SALE_QUERY_SCAN_MAX as usize)
+            .map(|((_, contract_and_token_id), _)| self.sales.get(&contract_and_token_id).unwrap())
+            .filter(|sale| sale.is_visible_to(viewer_id.as_ref()))
+            .take(limit as usize)
+            .map(|sale| sale.public_view())
+            .collect()
+    }
+
+    /// sales listed at or after since, oldest first, paged like get_sales
+    pub fn get_sales_listed_since(
+        &self,
+        nft_contract_id: Option<AccountId>,
+        since: U64,
+        limit: u64,
+        viewer_id: Option<AccountId>,
+        cursor: Option<ListingCursor>,
+    ) -> SalesPage {
+        self.get_sales(
+            SaleQuery {
+                nft_contract_id,
+                min_created_at: Some(since),
+                viewer_id,
+                cursor,
+                ..Default::default()
+            },
+            limit,
+        )
+    }
+
+    /// trade stats of a collection, rolling volumes are in day buckets with today's included
+    pub fn get_stats_by_nft_contract_id(&self, nft_contract_id: AccountId) -> MarketStatsView {
+        let stats = self
+            .stats_by_nft_contract_id
+            .get(&nft_contract_id)
+            .unwrap_or_default();
+        MarketStatsView::new(stats, current_time_ms())
     }
 
-    pub fn get_sale(&self, nft_contract_token: ContractAndTokenId) -> Option<Sale> {
-        self.sales.get(&nft_contract_token)
+    pub fn get_stats_by_nft_token_type(&self, token_type: String) -> MarketStatsView {
+        let stats = self
+            .stats_by_nft_token_type
+            .get(&token_type)
+            .unwrap_or_default();
+        MarketStatsView::new(stats, current_time_ms())
+    }
+}
+
+impl Contract {
+    /// limit sales of listings from from_index, without those viewer_id can't see
+    fn sales_from_index(
+        &self,
+        listings: Option<ListingIndex>,
+        from_index: U64,
+        limit: u64,
+        viewer_id: Option<&AccountId>,
+    ) -> Vec<Sale> {
+        let listings = if let Some(listings) = listings {
+            listings
+        } else {
+            return vec![];
+        };
+        listings
+            .iter()
+            .skip(from_index.0 as usize)
+            .take(limit as usize)
+            .map(|((_, contract_and_token_id), _)| self.sales.get(&contract_and_token_id).unwrap())
+            .filter(|sale| sale.is_visible_to(viewer_id))
+            .map(|sale| sale.public_view())
+            .collect()
     }
 }
@@ -0,0 +1,145 @@
+use crate::*;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+/// day buckets kept for rolling volumes, the longest window is 7 days
+const STATS_DAYS: u64 = 7;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DailyVolume {
+    /// days since the unix epoch
+    pub day: U64,
+    pub volume: SaleConditions,
+}
+
+/// trades of a collection or token type, volumes by FT
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketStats {
+    pub volume: SaleConditions,
+    pub trade_count: U64,
+    pub buyer_count: U64,
+    pub seller_count: U64,
+    /// the last STATS_DAYS days with trades, oldest first
+    pub daily_volumes: Vec<DailyVolume>,
+}
+
+impl Default for MarketStats {
+    fn default() -> Self {
+        Self {
+            volume: HashMap::new(),
+            trade_count: U64(0),
+            buyer_count: U64(0),
+            seller_count: U64(0),
+            daily_volumes: vec![],
+        }
+    }
+}
+
+impl MarketStats {
+    /// volume of the last days day buckets, today's included
+    pub fn rolling_volume(&self, days: u64, now: u64) -> SaleConditions {
+        let first_day = (now / DAY_MS + 1).saturating_sub(days);
+        let mut volume = SaleConditions::new();
+        for daily_volume in &self.daily_volumes {
+            if daily_volume.day.0 >= first_day {
+                for (ft_token_id, amount) in &daily_volume.volume {
+                    volume.entry(ft_token_id.clone()).or_insert(U128(0)).0 += amount.0;
+                }
+            }
+        }
+        volume
+    }
+}
+
+/// stats as returned by views
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketStatsView {
+    pub volume: SaleConditions,
+    pub trade_count: U64,
+    pub buyer_count: U64,
+    pub seller_count: U64,
+    pub volume_24h: SaleConditions,
+    pub volume_7d: SaleConditions,
+}
+
+impl MarketStatsView {
+    pub fn new(stats: MarketStats, now: u64) -> Self {
+        Self {
+            volume_24h: stats.rolling_volume(1, now),
+            volume_7d: stats.rolling_volume(STATS_DAYS, now),
+            volume: stats.volume,
+            trade_count: stats.trade_count,
+            buyer_count: stats.buyer_count,
+            seller_count: stats.seller_count,
+        }
+    }
+}
+
+impl Contract {
+    /// count a trade in the stats of its collection and, if the sale had one, its token type
+    pub(crate) fn internal_record_stats(&mut self, trade: &Trade, token_type: Option<&String>) {
+        let mut stats = self
+            .stats_by_nft_contract_id
+            .get(&trade.nft_contract_id)
+            .unwrap_or_default();
+        self.internal_update_stats(&mut stats, "contract", &trade.nft_contract_id, trade);
+        self.stats_by_nft_contract_id
+            .insert(&trade.nft_contract_id, &stats);
+
+        if let Some(token_type) = token_type {
+            let mut stats = self
+                .stats_by_nft_token_type
+                .get(token_type)
+                .unwrap_or_default();
+            self.internal_update_stats(&mut stats, "token_type", token_type, trade);
+            self.stats_by_nft_token_type.insert(token_type, &stats);
+        }
+    }
+
+    fn internal_update_stats(
+        &mut self,
+        stats: &mut MarketStats,
+        scope: &str,
+        stats_key: &str,
+        trade: &Trade,
+    ) {
+        stats
+            .volume
+            .entry(trade.ft_token_id.clone())
+            .or_insert(U128(0))
+            .0 += trade.price.0;
+        stats.trade_count = U64(stats.trade_count.0 + 1);
+        // traders are remembered as "scope||stats_key||role||account_id"
+        let buyer_key = [scope, stats_key, "buyer", &trade.buyer_id].join(DELIMETER);
+        if self.stats_traders.insert(&buyer_key) {
+            stats.buyer_count = U64(stats.buyer_count.0 + 1);
+        }
+        let seller_key = [scope, stats_key, "seller", &trade.seller_id].join(DELIMETER);
+        if self.stats_traders.insert(&seller_key) {
+            stats.seller_count = U64(stats.seller_count.0 + 1);
+        }
+
+        let day = trade.traded_at.0 / DAY_MS;
+        stats
+            .daily_volumes
+            .retain(|daily_volume| daily_volume.day.0 + STATS_DAYS > day);
+        if stats
+            .daily_volumes
+            .last()
+            .map_or(true, |last| last.day.0 != day)
+        {
+            stats.daily_volumes.push(DailyVolume {
+                day: U64(day),
+                volume: HashMap::new(),
+            });
+        }
+        let daily_volume = stats.daily_volumes.last_mut().unwrap();
+        daily_volume
+            .volume
+            .entry(trade.ft_token_id.clone())
+            .or_insert(U128(0))
+            .0 += trade.price.0;
+    }
+}
@@ -0,0 +1,368 @@
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    /// set once the token's owner approves the market with {"swap_id": "..."}
+    pub approval_id: Option<U64>,
+}
+
+/// NEAR or FTs paid into escrow by one side and sent to the other with the tokens
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapTopUp {
+    pub payer_id: AccountId,
+    pub ft_token_id: FungibleTokenId,
+    pub amount: U128,
+    pub is_paid: bool,
+}
+
+/// maker_tokens go to the taker and taker_tokens to the maker, together with any top-up,
+/// once every token is approved and the top-up is paid
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Swap {
+    pub swap_id: U64,
+    pub maker_id: AccountId,
+    pub taker_id: AccountId,
+    pub maker_tokens: Vec<SwapToken>,
+    pub taker_tokens: Vec<SwapToken>,
+    pub top_up: Option<SwapTopUp>,
+    pub created_at: U64,
+    pub expires_at: Option<U64>,
+}
+
+impl Swap {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at.0)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.maker_tokens
+            .iter()
+            .chain(&self.taker_tokens)
+            .all(|token| token.approval_id.is_some())
+            && self.top_up.as_ref().map_or(true, |top_up| top_up.is_paid)
+    }
+
+    /// each token with its owner and the account it's swapped to, maker_tokens first
+    pub fn transfers(&self) -> Vec<(&SwapToken, &AccountId, &AccountId)> {
+        self.maker_tokens
+            .iter()
+            .map(|token| (token, &self.maker_id, &self.taker_id))
+            .chain(
+                self.taker_tokens
+                    .iter()
+                    .map(|token| (token, &self.taker_id, &self.maker_id)),
+            )
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapTokenArgs {
+    pub nft_contract_id: ValidAccountId,
+    pub token_id: TokenId,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapTopUpArgs {
+    pub payer_id: ValidAccountId,
+    pub ft_token_id: ValidAccountId,
+    pub amount: U128,
+}
+
+/// approve a token, or pay a top-up in FTs through ft_on_transfer, with {"swap_id": "..."}
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapArgs {
+    pub swap_id: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// for approving tokens see: nft_callbacks.rs
+
+    /// the maker proposes trading maker_tokens for the taker's taker_tokens
+    pub fn propose_swap(
+        &mut self,
+        taker_id: ValidAccountId,
+        maker_tokens: Vec<SwapTokenArgs>,
+        taker_tokens: Vec<SwapTokenArgs>,
+        top_up: Option<SwapTopUpArgs>,
+        expires_at: Option<U64>,
+    ) -> U64 {
+        let maker_id = env::predecessor_account_id();
+        let taker_id: AccountId = taker_id.into();
+        assert_ne!(maker_id, taker_id, "Cannot swap with yourself");
+        assert!(
+            !maker_tokens.is_empty() && !taker_tokens.is_empty(),
+            "Both sides must swap tokens"
+        );
+        assert!(
+            maker_tokens.len() + taker_tokens.len() <= SWAP_SIZE_MAX as usize,
+            "Swaps have at most {} tokens",
+            SWAP_SIZE_MAX
+        );
+        let now = current_time_ms();
+        if let Some(expires_at) = expires_at {
+            assert!(expires_at.0 > now, "Swap expiry must be in the future");
+        }
+        let top_up = top_up.map(|top_up| {
+            let payer_id: AccountId = top_up.payer_id.into();
+            assert!(
+                payer_id == maker_id || payer_id == taker_id,
+                "Top-up must be paid by the maker or the taker"
+            );
+            if !self.ft_token_ids.contains(top_up.ft_token_id.as_ref()) {
+                env::panic(
+                    format!("Token {} not supported by this market", top_up.ft_token_id).as_bytes(),
+                );
+            }
+            assert!(top_up.amount.0 > 0, "Top-up must be greater than 0");
+            SwapTopUp {
+                payer_id,
+                ft_token_id: top_up.ft_token_id.into(),
+                amount: top_up.amount,
+                is_paid: false,
+            }
+        });
+        self.assert_storage_for_one_more(&maker_id);
+
+        let to_swap_tokens = |tokens: Vec<SwapTokenArgs>| -> Vec<SwapToken> {
+            tokens
+                .into_iter()
+                .map(|token| SwapToken {
+                    nft_contract_id: token.nft_contract_id.into(),
+                    token_id: token.token_id,
+                    approval_id: None,
+                })
+                .collect()
+        };
+        let swap_id = self.next_swap_id;
+        self.next_swap_id += 1;
+        self.swaps.insert(
+            &swap_id,
+            &Swap {
+                swap_id: U64(swap_id),
+                maker_id: maker_id.clone(),
+                taker_id: taker_id.clone(),
+                maker_tokens: to_swap_tokens(maker_tokens),
+                taker_tokens: to_swap_tokens(taker_tokens),
+                top_up,
+                created_at: U64(now),
+                expires_at,
+            },
+        );
+        self.internal_add_swap_index(&maker_id, swap_id);
+        self.internal_add_swap_index(&taker_id, swap_id);
+        U64(swap_id)
+    }
+
+    /// pay a NEAR top-up, FT top-ups are paid through ft_on_transfer
+    #[payable]
+    pub fn pay_swap_top_up(&mut self, swap_id: U64) {
+        self.internal_pay_swap_top_up(
+            swap_id.0,
+            "near".to_string(),
+            env::attached_deposit(),
+            env::predecessor_account_id(),
+        );
+    }
+
+    /// either side can cancel, and anyone once the swap expired. A paid top-up is refunded
+    #[payable]
+    pub fn cancel_swap(&mut self, swap_id: U64) {
+        assert_one_yocto();
+        let swap = self.swaps.get(&swap_id.0).expect("No swap");
+        let predecessor_id = env::predecessor_account_id();
+        assert!(
+            predecessor_id == swap.maker_id
+                || predecessor_id == swap.taker_id
+                || swap.is_expired(current_time_ms()),
+            "Must be the maker or the taker"
+        );
+        let swap = self.internal_remove_swap(swap_id.0);
+        if let Some(top_up) = swap.top_up.filter(|top_up| top_up.is_paid) {
+            transfer_to(&top_up.ft_token_id, &top_up.payer_id, top_up.amount);
+        }
+    }
+
+    /// self callback
+
+    /// the market holds every token that transferred, it sends them on to the other side if
+    /// all of them did, otherwise back to their owners and the top-up back to its payer
+    #[private]
+    pub fn resolve_swap(&mut self, swap: Swap) -> bool {
+        let transfers = swap.transfers();
+        let is_transferred: Vec<bool> = (0..transfers.len())
+            .map(|index| {
+                matches!(
+                    env::promise_result(index as u64),
+                    PromiseResult::Successful(_)
+                )
+            })
+            .collect();
+        let is_swapped = is_transferred.iter().all(|is_transferred| *is_transferred);
+
+        for ((token, owner_id, receiver_id), is_transferred) in transfers.iter().zip(is_transferred)
+        {
+            if is_transferred {
+                ext_contract::nft_transfer(
+                    if is_swapped { receiver_id } else { owner_id }.to_string(),
+                    token.token_id.clone(),
+                    None,
+                    None,
+                    &token.nft_contract_id,
+                    1,
+                    GAS_FOR_NFT_TRANSFER,
+                );
+            }
+        }
+
+        if let Some(top_up) = &swap.top_up {
+            let receiver_id = if !is_swapped {
+                &top_up.payer_id
+            } else if top_up.payer_id == swap.maker_id {
+                &swap.taker_id
+            } else {
+                &swap.maker_id
+            };
+            transfer_to(&top_up.ft_token_id, receiver_id, top_up.amount);
+        }
+        is_swapped
+    }
+}
+
+impl Contract {
+    /// record the approval of one of the swap's tokens and run the swap if it was the last step
+    pub(crate) fn internal_approve_swap_token(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+        swap_id: SwapId,
+    ) {
+        let mut swap = self.swaps.get(&swap_id).expect("No swap");
+        assert!(!swap.is_expired(current_time_ms()), "Swap has expired");
+        let tokens = if owner_id == swap.maker_id {
+            &mut swap.maker_tokens
+        } else if owner_id == swap.taker_id {
+            &mut swap.taker_tokens
+        } else {
+            env::panic(b"Must be the maker or the taker")
+        };
+        let token = tokens
+            .iter_mut()
+            .find(|token| token.nft_contract_id == nft_contract_id && token.token_id == token_id)
+            .expect("Token is not in the swap");
+        token.approval_id = Some(approval_id);
+        self.internal_remove_stale_sale(nft_contract_id, token_id);
+        self.swaps.insert(&swap_id, &swap);
+        self.internal_try_swap(swap_id);
+    }
+
+    pub(crate) fn internal_pay_swap_top_up(
+        &mut self,
+        swap_id: SwapId,
+        ft_token_id: AccountId,
+        amount: Balance,
+        payer_id: AccountId,
+    ) {
+        let mut swap = self.swaps.get(&swap_id).expect("No swap");
+        assert!(!swap.is_expired(current_time_ms()), "Swap has expired");
+        let top_up = swap.top_up.as_mut().expect("Swap has no top-up");
+        assert_eq!(
+            top_up.payer_id, payer_id,
+            "Top-up is paid by {}",
+            top_up.payer_id
+        );
+        assert!(!top_up.is_paid, "Top-up is already paid");
+        assert_eq!(
+            top_up.ft_token_id, ft_token_id,
+            "Top-up is paid in {}",
+            top_up.ft_token_id
+        );
+        assert_eq!(top_up.amount.0, amount, "Top-up is {}", top_up.amount.0);
+        top_up.is_paid = true;
+        self.swaps.insert(&swap_id, &swap);
+        self.internal_try_swap(swap_id);
+    }
+
+    /// once every token is approved and the top-up paid, move all the tokens to the market,
+    /// resolve_swap hands them on
+    fn internal_try_swap(&mut self, swap_id: SwapId) {
+        if !self.swaps.get(&swap_id).unwrap().is_ready() {
+            return;
+        }
+        let swap = self.internal_remove_swap(swap_id);
+        let market_id = env::current_account_id();
+        let transfers = swap
+            .transfers()
+            .into_iter()
+            .map(|(token, _, _)| {
+                ext_contract::nft_transfer(
+                    market_id.clone(),
+                    token.token_id.clone(),
+                    token.approval_id,
+                    None,
+                    &token.nft_contract_id,
+                    1,
+                    GAS_FOR_NFT_TRANSFER,
+                )
+            })
+            .reduce(|transfers, transfer| transfers.and(transfer))
+            .unwrap();
+        let gas_for_resolve = GAS_FOR_RESOLVE_SWAP
+            + GAS_FOR_NFT_TRANSFER * (swap.maker_tokens.len() + swap.taker_tokens.len()) as u64;
+        transfers.then(ext_self::resolve_swap(
+            swap,
+            &market_id,
+            NO_DEPOSIT,
+            gas_for_resolve,
+        ));
+    }
+
+    fn internal_add_swap_index(&mut self, account_id: &AccountId, swap_id: SwapId) {
+        let mut swaps_by_account_id =
+            self.swaps_by_account_id.get(account_id).unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::SwapsByAccountIdInner {
+                        account_id_hash: hash_account_id(account_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        swaps_by_account_id.insert(&swap_id);
+        self.swaps_by_account_id
+            .insert(account_id, &swaps_by_account_id);
+    }
+
+    fn internal_remove_swap_index(&mut self, account_id: &AccountId, swap_id: SwapId) {
+        let mut swaps_by_account_id = self
+            .swaps_by_account_id
+            .get(account_id)
+            .expect("No swap by account_id");
+        swaps_by_account_id.remove(&swap_id);
+        if swaps_by_account_id.is_empty() {
+            self.swaps_by_account_id.remove(account_id);
+        } else {
+            self.swaps_by_account_id
+                .insert(account_id, &swaps_by_account_id);
+        }
+    }
+
+    pub(crate) fn internal_remove_swap(&mut self, swap_id: SwapId) -> Swap {
+        let swap = self.swaps.remove(&swap_id).expect("No swap");
+        self.internal_remove_swap_index(&swap.maker_id, swap_id);
+        self.internal_remove_swap_index(&swap.taker_id, swap_id);
+        swap
+    }
+}
@@ -0,0 +1,45 @@
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// views
+    pub fn get_supply_swaps(&self) -> U64 {
+        U64(self.swaps.len())
+    }
+
+    pub fn get_swap(&self, swap_id: U64) -> Option<Swap> {
+        self.swaps.get(&swap_id.0)
+    }
+
+    pub fn get_supply_swaps_by_account_id(&self, account_id: AccountId) -> U64 {
+        let swaps_by_account_id = self.swaps_by_account_id.get(&account_id);
+        if let Some(swaps_by_account_id) = swaps_by_account_id {
+            U64(swaps_by_account_id.len())
+        } else {
+            U64(0)
+        }
+    }
+
+    /// swaps account_id made or is the taker of
+    pub fn get_swaps_by_account_id(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Swap> {
+        let mut tmp = vec![];
+        let swaps_by_account_id = self.swaps_by_account_id.get(&account_id);
+        let swaps = if let Some(swaps_by_account_id) = swaps_by_account_id {
+            swaps_by_account_id
+        } else {
+            return vec![];
+        };
+        let keys = swaps.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + limit, swaps.len());
+        for i in start..end {
+            tmp.push(self.swaps.get(&keys.get(i).unwrap()).unwrap());
+        }
+        tmp
+    }
+}
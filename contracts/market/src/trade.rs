@@ -0,0 +1,130 @@
+use crate::*;
+
+/// bytes a storage record takes on top of its key and value
+const STORAGE_RECORD_BYTES: StorageUsage = 40;
+/// the buyer, FT and referrer of a trade aren't known when it's reserved for
+const ACCOUNT_ID_MAX_LEN: StorageUsage = 64;
+
+/// a completed purchase, token_id is one item of a bundle for bundle purchases
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Trade {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub seller_id: AccountId,
+    pub buyer_id: AccountId,
+    pub ft_token_id: FungibleTokenId,
+    pub price: U128,
+    pub traded_at: U64,
+}
+
+/// most bytes a trade of the token by seller_id can take with its stats, referral stats and
+/// protocol fee, as if every record it touches were new
+pub(crate) fn trade_storage_max(
+    nft_contract_id: &str,
+    token_id: &str,
+    seller_id: &str,
+    token_type: Option<&String>,
+) -> StorageUsage {
+    let record = |key: StorageUsage, value: StorageUsage| key + value + STORAGE_RECORD_BYTES;
+    let string = |s: StorageUsage| 4 + s;
+    let id = ACCOUNT_ID_MAX_LEN;
+    let (nft_contract_id, token_id, seller_id) = (
+        nft_contract_id.len() as StorageUsage,
+        token_id.len() as StorageUsage,
+        seller_id.len() as StorageUsage,
+    );
+    // a history is a Vector behind a one byte prefix, its own prefix is a 33 byte StorageKey
+    let history = |key: StorageUsage| record(1 + string(key), 8 + string(33)) + record(33 + 8, 8);
+    // one FT in volume, the counts and a new day in daily_volumes
+    let stats = |key: StorageUsage| {
+        record(
+            1 + string(key),
+            4 + string(id) + 16 + 3 * 8 + 4 + 8 + 4 + string(id) + 16,
+        )
+    };
+    // stats_traders keys are "scope||key||role||account_id", kept twice by the set
+    let trader = |len: StorageUsage| record(2 + string(len), 8) + record(2 + 8, string(len));
+    let traders = |scope: StorageUsage, key: StorageUsage| {
+        trader(scope + key + 5 + id + 3 * 2) + trader(scope + key + 6 + seller_id + 3 * 2)
+    };
+
+    let mut bytes = record(
+        1 + 8,
+        string(nft_contract_id) + string(token_id) + string(seller_id) + 2 * string(id) + 16 + 8,
+    ) + history(nft_contract_id + 2 + token_id)
+        + history(seller_id)
+        + history(id)
+        + history(nft_contract_id)
+        + stats(nft_contract_id)
+        + traders(8, nft_contract_id)
+        + record(1 + string(id), 8 + 2 * (4 + string(id) + 16))
+        + record(1 + string(id), 2 * 16);
+    if let Some(token_type) = token_type {
+        let token_type = token_type.len() as StorageUsage;
+        bytes += stats(token_type) + traders(10, token_type);
+    }
+    bytes
+}
+
+impl Contract {
+    /// append a trade to the ledger and to the token's, both accounts' and the collection's
+    /// history. The last trade of a token is its last sale price
+    pub(crate) fn internal_record_trade(&mut self, trade: Trade) {
+        let trade_id = self.trades.len();
+        let contract_and_token_id =
+            format!("{}{}{}", trade.nft_contract_id, DELIMETER, trade.token_id);
+
+        let mut trades_by_token = self
+            .trades_by_token
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| {
+                Vector::new(
+                    StorageKey::TradesByTokenInner {
+                        token_hash: hash_account_id(&contract_and_token_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        trades_by_token.push(&trade_id);
+        self.trades_by_token
+            .insert(&contract_and_token_id, &trades_by_token);
+
+        for account_id in [&trade.seller_id, &trade.buyer_id] {
+            let mut trades_by_account_id = self
+                .trades_by_account_id
+                .get(account_id)
+                .unwrap_or_else(|| {
+                    Vector::new(
+                        StorageKey::TradesByAccountIdInner {
+                            account_id_hash: hash_account_id(account_id),
+                        }
+                        .try_to_vec()
+                        .unwrap(),
+                    )
+                });
+            trades_by_account_id.push(&trade_id);
+            self.trades_by_account_id
+                .insert(account_id, &trades_by_account_id);
+        }
+
+        let mut trades_by_nft_contract_id = self
+            .trades_by_nft_contract_id
+            .get(&trade.nft_contract_id)
+            .unwrap_or_else(|| {
+                Vector::new(
+                    StorageKey::TradesByNFTContractIdInner {
+                        account_id_hash: hash_account_id(&trade.nft_contract_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        trades_by_nft_contract_id.push(&trade_id);
+        self.trades_by_nft_contract_id
+            .insert(&trade.nft_contract_id, &trades_by_nft_contract_id);
+
+        self.trades.push(&trade);
+    }
+}
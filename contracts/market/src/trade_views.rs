@@ -0,0 +1,100 @@
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// views, trade histories are oldest first
+    pub fn get_supply_trades(&self) -> U64 {
+        U64(self.trades.len())
+    }
+
+    pub fn get_trades(&self, from_index: U64, limit: u64) -> Vec<Trade> {
+        let start = u64::from(from_index);
+        let end = min(start + limit, self.trades.len());
+        (start..end).map(|i| self.trades.get(i).unwrap()).collect()
+    }
+
+    /// the last trade of a token, None if it never sold on this market
+    pub fn get_last_sale(&self, nft_contract_token: ContractAndTokenId) -> Option<Trade> {
+        let trades_by_token = self.trades_by_token.get(&nft_contract_token)?;
+        let trade_id = trades_by_token.get(trades_by_token.len() - 1).unwrap();
+        self.trades.get(trade_id)
+    }
+
+    pub fn get_supply_trades_by_token(&self, nft_contract_token: ContractAndTokenId) -> U64 {
+        self.trades_by_token
+            .get(&nft_contract_token)
+            .map_or(U64(0), |trades| U64(trades.len()))
+    }
+
+    pub fn get_trades_by_token(
+        &self,
+        nft_contract_token: ContractAndTokenId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Trade> {
+        self.trades_page(
+            self.trades_by_token.get(&nft_contract_token),
+            from_index,
+            limit,
+        )
+    }
+
+    pub fn get_supply_trades_by_account_id(&self, account_id: AccountId) -> U64 {
+        self.trades_by_account_id
+            .get(&account_id)
+            .map_or(U64(0), |trades| U64(trades.len()))
+    }
+
+    /// trades account_id bought or sold in
+    pub fn get_trades_by_account_id(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Trade> {
+        self.trades_page(
+            self.trades_by_account_id.get(&account_id),
+            from_index,
+            limit,
+        )
+    }
+
+    pub fn get_supply_trades_by_nft_contract_id(&self, nft_contract_id: AccountId) -> U64 {
+        self.trades_by_nft_contract_id
+            .get(&nft_contract_id)
+            .map_or(U64(0), |trades| U64(trades.len()))
+    }
+
+    pub fn get_trades_by_nft_contract_id(
+        &self,
+        nft_contract_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Trade> {
+        self.trades_page(
+            self.trades_by_nft_contract_id.get(&nft_contract_id),
+            from_index,
+            limit,
+        )
+    }
+}
+
+impl Contract {
+    fn trades_page(
+        &self,
+        trade_ids: Option<Vector<TradeId>>,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<Trade> {
+        let trade_ids = if let Some(trade_ids) = trade_ids {
+            trade_ids
+        } else {
+            return vec![];
+        };
+        let start = u64::from(from_index);
+        let end = min(start + limit, trade_ids.len());
+        (start..end)
+            .map(|i| self.trades.get(trade_ids.get(i).unwrap()).unwrap())
+            .collect()
+    }
+}
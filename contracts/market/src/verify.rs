@@ -0,0 +1,114 @@
+use crate::*;
+
+/// the part of an nft_token result the market checks
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenOwner {
+    pub owner_id: AccountId,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// anyone can check a sale against its NFT contract, the sale is removed if the seller no
+    /// longer owns the token or the market's approval_id was revoked
+    pub fn verify_sale(&mut self, nft_contract_id: ValidAccountId, token_id: String) -> Promise {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        self.internal_verify_sale(sale)
+    }
+
+    /// verify limit sales of nft_contract_id from from_index, each one resolves on its own
+    pub fn verify_sales(&mut self, nft_contract_id: ValidAccountId, from_index: U64, limit: u64) {
+        let by_nft_contract_id = self
+            .by_nft_contract_id
+            .get(nft_contract_id.as_ref())
+            .expect("No sales by nft_contract_id");
+        let start = u64::from(from_index);
+        let end = min(start + limit, by_nft_contract_id.len());
+        assert!(
+            env::prepaid_gas() >= GAS_FOR_VERIFY_SALE * end.saturating_sub(start),
+            "Attach at least {} gas per sale",
+            GAS_FOR_VERIFY_SALE
+        );
+        let sales: Vec<Sale> = by_nft_contract_id
+            .iter()
+            .skip(start as usize)
+            .take(end.saturating_sub(start) as usize)
+            .map(|((_, contract_and_token_id), _)| self.sales.get(&contract_and_token_id).unwrap())
+            .collect();
+        for sale in sales {
+            self.internal_verify_sale(sale);
+        }
+    }
+
+    /// self callback
+
+    /// removes the sale only if the NFT contract answered that it is invalid, returns whether
+    /// the sale is still listed
+    #[private]
+    pub fn resolve_verify_sale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+    ) -> bool {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        // the sale may have been bought, removed or listed again while verifying
+        match self.sales.get(&contract_and_token_id) {
+            Some(sale) if sale.approval_id == approval_id => {}
+            _ => return false,
+        }
+
+        let is_owner = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<Option<TokenOwner>>(&value)
+                    .ok()
+                    .map(|token| token.map_or(false, |token| token.owner_id == owner_id))
+            }
+            _ => None,
+        };
+        let is_approved = match env::promise_result(1) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<bool>(&value).ok()
+            }
+            _ => None,
+        };
+        if is_owner == Some(false) || is_approved == Some(false) {
+            let sale = self.internal_remove_sale(nft_contract_id, token_id);
+            self.refund_all_bids(&contract_and_token_id, &sale.bids);
+            self.refund_sealed_bids(&sale);
+            return false;
+        }
+        true
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_verify_sale(&mut self, sale: Sale) -> Promise {
+        let market_id = env::current_account_id();
+        ext_contract::nft_token(
+            sale.token_id.clone(),
+            &sale.nft_contract_id,
+            NO_DEPOSIT,
+            GAS_FOR_NFT_VIEW,
+        )
+        .and(ext_contract::nft_is_approved(
+            sale.token_id.clone(),
+            market_id.clone(),
+            Some(sale.approval_id),
+            &sale.nft_contract_id,
+            NO_DEPOSIT,
+            GAS_FOR_NFT_VIEW,
+        ))
+        .then(ext_self::resolve_verify_sale(
+            sale.nft_contract_id,
+            sale.token_id,
+            sale.owner_id,
+            sale.approval_id,
+            &market_id,
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_VERIFY_SALE,
+        ))
+    }
+}